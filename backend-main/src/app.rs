@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -8,34 +9,46 @@ use axum::extract::{Path, Request, State, WebSocketUpgrade};
 use axum::http::{Response, StatusCode};
 use axum::middleware::{from_fn, Next};
 use axum::response::IntoResponse;
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post};
 use axum::{Extension, Json, Router};
 use axum_extra::extract::cookie::{Cookie, SameSite};
 use axum_extra::extract::CookieJar;
 use jwt_simple::claims::Claims;
 use jwt_simple::prelude::{Ed25519KeyPair, EdDSAKeyPairLike, EdDSAPublicKeyLike};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use time::{Date, Month, OffsetDateTime};
 use tokio::fs::File;
-use tokio::sync::mpsc::error::TrySendError;
+use tokio::select;
+use tokio::sync::TryAcquireError;
 use tokio::task::spawn_blocking;
 use tokio_util::sync::CancellationToken;
 use tower_http::trace::TraceLayer;
-use tracing::{debug, info, info_span, trace, Span};
+use tracing::{debug, error, info, info_span, trace, warn, Span};
 use ulid::Ulid;
 
-use crate::auth::{OauthRefreshResponseResult, TokenClaim};
-use crate::config::{Config, ImageSizeLimits};
-use crate::error::{UploadError, WsError};
+use crate::auth::{refresh_token, TokenClaim};
+use crate::config::{
+    Config, Fit, FrameConfig, ImageSizeLimits, LayoutField, LyricsMode, YoutubeScope,
+};
+use crate::cue::{parse_cue, CueSheet};
+use crate::error::{CancelError, UploadError, WsError};
 use crate::ffmpeg::*;
-use crate::ffprobe::get_duration_ffprobe;
-use crate::util::{decode_image, get_file_info, remove_failed_upload, take_upload};
+use crate::ffprobe::{probe_audio, render_template, AudioTags, CoverStream};
+use crate::lyrics::{fit_lyrics, parse_lyrics, to_srt, LyricLine};
+use crate::store::JobStore;
+use crate::util::{
+    audio_extension, decode_image, extract_cover, get_file_info, parse_hex_color,
+    remove_failed_upload, set_extension, solid_image, take_upload, valid_color,
+};
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: &'static Config,
-    pub job_sender: JobSender,
+    pub job_queue: Arc<JobQueue>,
     pub job_tracker: Arc<JobTracker>,
+    pub job_store: JobStore,
     pub cancellation_token: CancellationToken,
     pub keypair: Ed25519KeyPair,
     pub reqwest: reqwest::Client,
@@ -44,6 +57,20 @@ pub struct AppState {
 //pub const HEADROOM: u64 = 500_000;
 pub const MAX_TITLE: usize = 100;
 pub const MAX_DESC: usize = 5000;
+pub const MAX_LYRICS: usize = 512 * 1024;
+pub const MAX_PLAYLISTS: usize = 10;
+/// leaves room for the queue, rendering and the upload
+pub const MIN_PUBLISH_DELAY: Duration = Duration::from_secs(60 * 60);
+/// youtube rejects schedules further out than this
+pub const MAX_PUBLISH_DELAY: Duration = Duration::from_days(365);
+
+/// an `audio` field with the `track_*` fields that followed it
+struct TrackUpload {
+    name: String,
+    path: Arc<std::path::Path>,
+    title: Option<String>,
+    cover: Option<(Arc<std::path::Path>, File)>,
+}
 
 async fn request_id(mut request: Request, next: Next) -> impl IntoResponse {
     request.extensions_mut().insert(Ulid::new());
@@ -54,9 +81,10 @@ async fn upload(
     Extension(id): Extension<Ulid>,
     State(AppState {
         config,
-        job_sender,
+        job_queue,
         cancellation_token,
         job_tracker,
+        job_store,
         keypair,
         reqwest: client,
         ..
@@ -75,7 +103,20 @@ async fn upload(
         .map_err(|_| UploadError::InvalidJWT("signature"))?;
 
     let mut image_file = None;
-    let mut audio_file = None;
+    let mut thumbnail_file = None;
+    let mut audio_files: Vec<TrackUpload> = Vec::new();
+    let mut cue: Option<CueSheet> = None;
+    let mut lyrics: Option<Vec<LyricLine>> = None;
+    let mut lyrics_mode = config.lyrics.mode;
+    let mut playlists = Vec::new();
+    let mut publish_at = None;
+    let mut default_language = None;
+    let mut default_audio_language = None;
+    let mut license = None;
+    let mut embeddable = None;
+    let mut public_stats_viewable = None;
+    let mut recording_date = None;
+    let mut localizations: BTreeMap<String, Localization> = BTreeMap::new();
     let mut title_field = None;
     let mut desc_field = None;
     let mut privacy = Privacy::default();
@@ -83,6 +124,9 @@ async fn upload(
     let mut category = 10; // Music
     let mut made_for_kids = false;
     let mut notify_subs = false;
+    let mut encoding = config.encoding.default_preset();
+    let mut normalize = config.encoding.loudness.normalize;
+    let mut layout = Layout::default();
 
     while let Some(mut field) = multipart.next_field().await? {
         let field_name = field
@@ -91,7 +135,7 @@ async fn upload(
 
         match field_name {
             "image" => {
-                let (file_name, output_path) = get_file_info(&field, &config, id, "image")?;
+                let (_, output_path) = get_file_info(&field, config, id, "image")?;
 
                 let mut file = File::create_new(&output_path).await?;
                 remove_failed_upload(
@@ -106,10 +150,17 @@ async fn upload(
                     &*output_path,
                 )
                 .await?;
-                image_file = Some((file_name, output_path, file));
+                image_file = Some((output_path, file));
             }
             "audio" => {
-                let (file_name, output_path) = get_file_info(&field, &config, id, "audio")?;
+                if audio_files.len() >= config.limits.upload.max_tracks {
+                    return Err(UploadError::BadRequest("too many audio files"));
+                }
+                let kind = match audio_files.len() {
+                    0 => Cow::Borrowed("audio"),
+                    n => Cow::Owned(format!("audio{n}")),
+                };
+                let (file_name, output_path) = get_file_info(&field, config, id, &kind)?;
 
                 let mut file = File::create_new(&output_path).await?;
                 remove_failed_upload(
@@ -124,7 +175,91 @@ async fn upload(
                     &*output_path,
                 )
                 .await?;
-                audio_file = Some((file_name, output_path, file));
+                audio_files.push(TrackUpload {
+                    name: file_name,
+                    path: output_path,
+                    title: None,
+                    cover: None,
+                });
+            }
+            "thumbnail" => {
+                let (_, output_path) = get_file_info(&field, config, id, "thumbnail")?;
+
+                let mut file = File::create_new(&output_path).await?;
+                remove_failed_upload(
+                    take_upload(
+                        &mut field,
+                        &mut file,
+                        "thumbnail",
+                        config.limits.upload.max_image,
+                    )
+                    .await,
+                    &mut file,
+                    &*output_path,
+                )
+                .await?;
+                thumbnail_file = Some((output_path, file));
+            }
+            "track_title" => {
+                let track = audio_files
+                    .last_mut()
+                    .ok_or(UploadError::BadRequest("track_title before any audio file"))?;
+                let text = field.text().await?;
+                if text.len() > MAX_TITLE {
+                    return Err(UploadError::BadRequest("track title too long"));
+                }
+                track.title = Some(text);
+            }
+            "track_image" => {
+                let n = audio_files.len();
+                let track = audio_files
+                    .last_mut()
+                    .ok_or(UploadError::BadRequest("track_image before any audio file"))?;
+                let (_, output_path) = get_file_info(&field, config, id, &format!("image{n}"))?;
+
+                let mut file = File::create_new(&output_path).await?;
+                remove_failed_upload(
+                    take_upload(
+                        &mut field,
+                        &mut file,
+                        "image",
+                        config.limits.upload.max_image,
+                    )
+                    .await,
+                    &mut file,
+                    &*output_path,
+                )
+                .await?;
+                track.cover = Some((output_path, file));
+            }
+            "cue" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                cue = Some(parse_cue(&text)?);
+            }
+            "lyrics" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                if text.len() > MAX_LYRICS {
+                    return Err(UploadError::FileTooLarge {
+                        subject: "lyrics",
+                        max: MAX_LYRICS as u64,
+                    });
+                }
+                lyrics = Some(parse_lyrics(&text)?);
+            }
+            "lyrics_mode" => {
+                let text = field.text().await?;
+                lyrics_mode = match text.as_str() {
+                    "" => continue,
+                    "burn" => LyricsMode::Burn,
+                    "caption" => LyricsMode::Caption,
+                    _ => return Err(UploadError::BadRequest("invalid lyrics_mode value")),
+                };
             }
             "title" => {
                 let text = field.text().await?;
@@ -144,6 +279,26 @@ async fn upload(
                     .parse()
                     .map_err(|_| UploadError::BadRequest("inalid privacy value"))?;
             }
+            "publish_at" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                let at = OffsetDateTime::parse(&text, &Rfc3339)
+                    .map_err(|_| UploadError::BadRequest("publish_at is not an RFC 3339 time"))?;
+                let now = OffsetDateTime::now_utc();
+                if at < now + MIN_PUBLISH_DELAY {
+                    return Err(UploadError::BadRequest(
+                        "publish_at has to be at least an hour in the future",
+                    ));
+                }
+                if at > now + MAX_PUBLISH_DELAY {
+                    return Err(UploadError::BadRequest(
+                        "publish_at is too far in the future",
+                    ));
+                }
+                publish_at = Some(at);
+            }
             "tags" => {
                 let text = field.text().await?;
                 if text.is_empty() {
@@ -161,6 +316,21 @@ async fn upload(
                     return Err(UploadError::BadRequest("tags too long"));
                 }
             }
+            "playlists" => {
+                let text = field.text().await?;
+                playlists = text
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    // `name#3` is resolved against the existing playlists after the upload
+                    .map(|line| PlaylistTarget {
+                        name: line.to_owned(),
+                    })
+                    .collect();
+                if playlists.len() > MAX_PLAYLISTS {
+                    return Err(UploadError::BadRequest("too many playlists"));
+                }
+            }
             "category" => {
                 let text = field.text().await?;
                 if text.is_empty() {
@@ -172,6 +342,70 @@ async fn upload(
                     .and_then(|n| CATEGORY_IDS.contains(&n).then_some(n))
                     .ok_or(UploadError::BadRequest("invalid category number"))?;
             }
+            "default_language" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                if !valid_language(&text) {
+                    return Err(UploadError::BadRequest("invalid default_language"));
+                }
+                default_language = Some(text);
+            }
+            "default_audio_language" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                if !valid_language(&text) {
+                    return Err(UploadError::BadRequest("invalid default_audio_language"));
+                }
+                default_audio_language = Some(text);
+            }
+            "license" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                license = Some(text.parse().map_err(|_| {
+                    UploadError::BadRequest("license has to be youtube or creativeCommon")
+                })?);
+            }
+            "embeddable" => {
+                let text = field.text().await?;
+                embeddable =
+                    switch(&text).ok_or(UploadError::BadRequest("invalid embeddable value"))?;
+            }
+            "public_stats_viewable" => {
+                let text = field.text().await?;
+                public_stats_viewable = switch(&text).ok_or(UploadError::BadRequest(
+                    "invalid public_stats_viewable value",
+                ))?;
+            }
+            "recording_date" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                let date = OffsetDateTime::parse(&text, &Rfc3339)
+                    .ok()
+                    .or_else(|| Some(parse_date(&text)?.midnight().assume_utc()))
+                    .ok_or(UploadError::BadRequest(
+                        "recording_date has to be a date or an RFC 3339 time",
+                    ))?;
+                if date > OffsetDateTime::now_utc() {
+                    return Err(UploadError::BadRequest("recording_date is in the future"));
+                }
+                recording_date = Some(date);
+            }
+            "localizations" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                localizations = serde_json::from_str(&text)
+                    .map_err(|_| UploadError::BadRequest("invalid localizations json"))?;
+            }
             "made_for_kids" => {
                 let text = field.text().await?;
                 made_for_kids = text == "on";
@@ -180,28 +414,207 @@ async fn upload(
                 let text = field.text().await?;
                 notify_subs = text == "on";
             }
+            "normalize" => {
+                let text = field.text().await?;
+                normalize = match text.as_str() {
+                    "" => continue,
+                    "on" | "true" => true,
+                    "off" | "false" => false,
+                    _ => return Err(UploadError::BadRequest("invalid normalize value")),
+                };
+            }
+            "preset" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                encoding = config
+                    .encoding
+                    .allowed
+                    .contains(&text)
+                    .then(|| config.encoding.presets.get(&text))
+                    .flatten()
+                    .ok_or(UploadError::BadRequest("invalid encoding preset"))?;
+            }
+            "fit" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                layout.fit = Some(
+                    text.parse()
+                        .map_err(|_| UploadError::BadRequest("invalid fit"))?,
+                );
+            }
+            "frame" => {
+                let text = field.text().await?;
+                if let Some(enable) =
+                    switch(&text).ok_or(UploadError::BadRequest("invalid frame value"))?
+                {
+                    layout.enable = Some(enable);
+                }
+            }
+            "frame_color" | "void_color" => {
+                let is_frame = field_name == "frame_color";
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                match is_frame {
+                    true => layout.frame_color = Some(text),
+                    false => layout.void_color = Some(text),
+                }
+            }
+            "image_size" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                layout.image_size = Some(
+                    text.split_once('x')
+                        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                        .ok_or(UploadError::BadRequest("image_size has to be WIDTHxHEIGHT"))?,
+                );
+            }
+            "layout" => {
+                let text = field.text().await?;
+                if text.is_empty() {
+                    continue;
+                }
+                let json: Layout = serde_json::from_str(&text)
+                    .map_err(|_| UploadError::BadRequest("invalid layout json"))?;
+                layout.merge(json);
+            }
             v => {
                 trace!("unknown field {v:?}");
             }
         };
     }
 
-    let (image_name, image_path, image_fd) =
-        image_file.ok_or(UploadError::BadRequest("no image file"))?;
-
-    let (audio_name, audio_path, audio_fd) =
-        audio_file.ok_or(UploadError::BadRequest("no audio file"))?;
-    drop(audio_fd);
-    let meta_filename = audio_name.clone();
-
     let length_limits = config.limits.audio;
-    let time = get_duration_ffprobe(&*audio_path).await?;
-    if !time.is_normal() && time != 0.0 {
-        return Err(UploadError::AudioMisc {
-            code: "invalid_duration",
-            message: "duration is not a valid number",
-        });
+    let (audio_path, time, codec, tracks, embedded, audio_tags, prefill) = match audio_files.len() {
+        0 => return Err(UploadError::BadRequest("no audio file")),
+        1 => {
+            let track = &audio_files[0];
+            if track.title.is_some() || track.cover.is_some() {
+                return Err(UploadError::BadRequest(
+                    "track fields need more than one audio file",
+                ));
+            }
+            let probe = probe_audio(&*track.path).await?;
+            let path = check_audio(config, &track.path, &probe.format).await?;
+            let time = check_duration(probe.duration)?;
+            let prefill = prefill(config, &probe.tags);
+            let embedded = probe.cover.map(|cover| (path.clone(), cover));
+            (
+                path,
+                time,
+                probe.codec,
+                Vec::new(),
+                embedded,
+                probe.tags,
+                prefill,
+            )
+        }
+        _ => {
+            let mut tracks = Vec::with_capacity(audio_files.len());
+            let mut embedded = None;
+            let mut album_tags = AudioTags::default();
+            for track in audio_files.iter_mut() {
+                let probe = probe_audio(&*track.path).await?;
+                track.path = check_audio(config, &track.path, &probe.format).await?;
+                if tracks.is_empty() {
+                    embedded = probe.cover.map(|cover| (track.path.clone(), cover));
+                    // the title and isrc belong to the first track, not the album
+                    album_tags = AudioTags {
+                        title: None,
+                        isrc: None,
+                        ..probe.tags.clone()
+                    };
+                }
+                let length = check_duration(probe.duration)?;
+                if length == 0.0 {
+                    return Err(UploadError::AudioMisc {
+                        code: "invalid_duration",
+                        message: "album tracks can't be empty",
+                    });
+                }
+                let cover = match track.cover.take() {
+                    Some((path, fd)) => {
+                        let (path, size) = check_image(config, path, fd).await?;
+                        Some(TrackCover { path, size })
+                    }
+                    None => None,
+                };
+                let title = track.title.take().or(probe.tags.title).unwrap_or_else(|| {
+                    std::path::Path::new(&track.name)
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().into_owned())
+                        .unwrap_or_default()
+                });
+                tracks.push(Track {
+                    title,
+                    audio_path: track.path.clone(),
+                    length,
+                    cover,
+                });
+            }
+            let time = tracks.iter().map(|track| track.length).sum();
+            let album_path = config.temp_dir.join(format!("album_{id}.mka")).into();
+            // the album stands in for the title in the templates
+            let prefill = prefill(
+                config,
+                &AudioTags {
+                    title: album_tags.album.clone(),
+                    ..album_tags.clone()
+                },
+            );
+            // the tracks are joined into flac before rendering
+            (
+                album_path,
+                time,
+                "flac".to_owned(),
+                tracks,
+                embedded,
+                album_tags,
+                prefill,
+            )
+        }
+    };
+    title_field = title_field
+        .filter(|title| !title.is_empty())
+        .or(prefill.title);
+    desc_field = desc_field
+        .filter(|desc| !desc.is_empty())
+        .or(prefill.description);
+    if tags.is_empty() {
+        tags = prefill.tags;
     }
+    let meta_filename = audio_files[0].name.clone();
+
+    let chapters = match cue {
+        Some(_) if !tracks.is_empty() => {
+            return Err(UploadError::BadRequest(
+                "cue sheets only work with a single audio file",
+            ))
+        }
+        Some(cue) => cue.chapters(time)?,
+        None => {
+            let mut start = 0.0;
+            tracks
+                .iter()
+                .map(|track| {
+                    let chapter = Chapter {
+                        title: track.title.clone(),
+                        start,
+                        end: start + track.length,
+                    };
+                    start = chapter.end;
+                    chapter
+                })
+                .collect()
+        }
+    };
 
     if !(length_limits.min..=length_limits.max).contains(&time) {
         return Err(UploadError::AudioLength {
@@ -210,35 +623,79 @@ async fn upload(
         });
     }
 
-    let image_limits = config.limits.image;
-    let ImageSizeLimits {
-        min_resolution: (min_width, min_height),
-        max_resolution: (max_width, max_height),
-        max_decoded,
-    } = image_limits;
-    let image_fd = image_fd.into_std().await;
-    let ((width, height), decoded_size) =
-        spawn_blocking(move || decode_image(image_name, image_fd)).await??;
+    if lyrics.is_some()
+        && lyrics_mode == LyricsMode::Caption
+        // captions.insert doesn't accept the other scopes
+        && !claims
+            .custom
+            .scope
+            .iter()
+            .any(|scope| scope == YoutubeScope::ForceSsl.url())
+    {
+        return Err(UploadError::BadRequest(
+            "uploading captions needs the youtube.force-ssl scope",
+        ));
+    }
+    if !playlists.is_empty()
+        && !claims.custom.scope.iter().any(|scope| {
+            scope == YoutubeScope::Youtube.url() || scope == YoutubeScope::ForceSsl.url()
+        })
+    {
+        return Err(UploadError::BadRequest(
+            "adding to playlists needs the youtube or youtube.force-ssl scope",
+        ));
+    }
+    if let Some(lines) = lyrics.as_mut() {
+        fit_lyrics(lines, time)?;
+    }
 
-    #[allow(clippy::manual_range_contains)] // it is worse
-    if width < min_width || width > max_width || height < min_height || height > max_height {
-        return Err(UploadError::ImageDimensions {
-            value: (width, height),
-            expected: image_limits,
-        });
-    } else if decoded_size > max_decoded {
-        return Err(UploadError::FileTooLarge {
-            subject: "image_decoded",
-            max: max_decoded,
-        });
+    let mut frame = frame_layout(config, layout)?;
+    let (image_path, (width, height)) = match image_file {
+        Some((path, fd)) => check_image(config, path, fd).await?,
+        None => default_cover(config, id, embedded, frame.image_size).await?,
+    };
+    let thumbnail = match thumbnail_file {
+        Some((path, fd)) => Some(check_image(config, path, fd).await?.0),
+        None => None,
+    };
+
+    if !localizations.is_empty() && default_language.is_none() {
+        return Err(UploadError::BadRequest(
+            "localizations need a default_language",
+        ));
+    }
+    for (language, localization) in localizations.iter_mut() {
+        if !valid_language(language) {
+            return Err(UploadError::BadRequest("invalid localization language"));
+        }
+        if localization.title.is_empty() || localization.title.len() > MAX_TITLE {
+            return Err(UploadError::BadRequest("invalid localized title length"));
+        }
+        if localization.description.len() > MAX_DESC - config.description_watermark.len() - 3 {
+            return Err(UploadError::BadRequest("localized description too long"));
+        }
+        localization.description = if localization.description.is_empty() {
+            config.description_watermark.clone()
+        } else {
+            format!(
+                "{}\n\n{}",
+                localization.description, config.description_watermark
+            )
+        };
     }
 
-    let permit = match job_sender.try_reserve() {
+    if publish_at.is_some() && privacy != Privacy::Private {
+        // youtube only schedules private videos
+        debug!(?privacy, "scheduled video, uploading as private");
+        privacy = Privacy::Private;
+    }
+
+    let slot = match job_queue.try_reserve() {
         Ok(v) => v,
-        Err(TrySendError::Full(_)) => {
+        Err(TryAcquireError::NoPermits) => {
             return Err(UploadError::QueueFull(config.limits.processing.queue_size))
         }
-        Err(TrySendError::Closed(_)) => {
+        Err(TryAcquireError::Closed) => {
             cancellation_token.cancel();
             return Err(UploadError::ChannelClosed);
         }
@@ -246,31 +703,15 @@ async fn upload(
 
     let mut c = claims.custom;
 
-    // check if it's going to expire and regen token
-    let now = OffsetDateTime::now_utc();
-    if now
-        > c.expires_at // ideally do saturating_sub but `time` is kinda stupid
-            - Duration::from_millis(config.limits.processing.time)
-                * config.limits.processing.queue_size as u32
-    {
-        let res = client
-            .post(config.auth.token_uri.as_str())
-            .form(&[
-                ("grant_type", "refresh_token"),
-                ("client_id", &config.auth.client_id),
-                ("client_secret", &config.auth.client_secret),
-                ("refresh_token", &c.refresh_token),
-            ])
-            .send()
-            .await?;
-        let bytes = res.bytes().await?;
-        let parsed: OauthRefreshResponseResult = serde_json::from_slice(&bytes)?;
-        drop(bytes);
-        let data = Result::from(parsed)?;
-
-        c.access_token = data.access_token;
-        c.expires_at = OffsetDateTime::now_utc()
-            + Duration::from_secs((data.expires_in as u64).saturating_sub(60));
+    // make sure it outlasts a full queue
+    let margin = Duration::from_millis(
+        config
+            .limits
+            .processing
+            .time
+            .max(config.limits.processing.visualizer_time),
+    ) * config.limits.processing.queue_size as u32;
+    if refresh_token(&mut c, margin, &config.auth, &client).await? {
         let new_claims =
             Claims::with_custom_claims(c.clone(), jwt_simple::prelude::Duration::from_days(90));
         match keypair.sign(new_claims) {
@@ -287,10 +728,51 @@ async fn upload(
         }
     }
 
-    let description = match desc_field {
-        Some(desc) => format!("{}\n\n{}", &desc, &config.description_watermark),
+    let audio = AudioMode::choose(&codec, encoding, normalize);
+    info!(?audio, "audio stream");
+
+    let mut description = match desc_field {
+        Some(desc) => format!("{}\n\n{}", desc, config.description_watermark),
         None => config.description_watermark.clone(),
     };
+    if !chapters.is_empty() {
+        description = format!("{}\n\n{description}", tracklist(&chapters));
+        if description.len() > MAX_DESC {
+            return Err(UploadError::BadRequest(
+                "description too long to fit the tracklist",
+            ));
+        }
+    }
+
+    let video_title = title_field.clone().unwrap_or_else(|| meta_filename.clone());
+    frame.text.retain_mut(|layer| {
+        let text = render_template(&layer.text, |key| match key {
+            "video_title" => Some(video_title.as_str()),
+            key => audio_tags.get(key),
+        });
+        match text {
+            Some(text) => {
+                layer.text = text;
+                true
+            }
+            None => false,
+        }
+    });
+
+    // written last so a rejected upload doesn't leave it behind
+    let lyrics = match lyrics {
+        Some(lines) => {
+            let path: Arc<std::path::Path> =
+                config.temp_dir.join(format!("lyrics_{id}.srt")).into();
+            tokio::fs::write(&path, to_srt(&lines)).await?;
+            Some(Lyrics {
+                path,
+                mode: lyrics_mode,
+                config: Arc::new(config.lyrics.clone()),
+            })
+        }
+        None => None,
+    };
 
     let job_info = JobInfo {
         id,
@@ -299,9 +781,20 @@ async fn upload(
 
         audio_path,
         audio_length: time,
-        output_path: config.temp_dir.join(format!("output_{id}.mkv")).into(),
-        frame: Arc::new(config.frame.clone()),
+        audio,
+        normalize: normalize.then_some(config.encoding.loudness),
+        tracks,
+        chapters,
+        lyrics,
+        thumbnail,
+        playlists,
+        output_path: config
+            .temp_dir
+            .join(format!("output_{id}.{}", encoding.container.extension()))
+            .into(),
+        frame: Arc::new(frame),
         limits: Arc::new(config.limits),
+        encoding: Arc::new(encoding.clone()),
         meta: Metadata {
             filename: meta_filename,
             title: title_field,
@@ -311,11 +804,19 @@ async fn upload(
             category,
             made_for_kids,
             notify_subs,
+            publish_at,
+            default_language,
+            default_audio_language,
+            license,
+            embeddable,
+            public_stats_viewable,
+            recording_date,
+            localizations,
         },
         auth: c,
     };
 
-    submit_job(job_info, permit, &job_tracker).await;
+    submit_job(job_info, slot, &job_tracker, &job_store).await?;
 
     info!("job submitted");
 
@@ -351,44 +852,504 @@ async fn upload(
     ))
 }
 
+/// checkbox style values, `None` for an empty field
+fn switch(text: &str) -> Option<Option<bool>> {
+    match text {
+        "" => Some(None),
+        "on" | "true" => Some(Some(true)),
+        "off" | "false" => Some(Some(false)),
+        _ => None,
+    }
+}
+
+/// `YYYY-MM-DD`
+fn parse_date(text: &str) -> Option<Date> {
+    let mut parts = text.splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = Month::try_from(parts.next()?.parse::<u8>().ok()?).ok()?;
+    let day = parts.next()?.parse().ok()?;
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+/// loose BCP-47 check, `en`, `pt-BR`, `zh-Hant-TW`...
+fn valid_language(tag: &str) -> bool {
+    let mut parts = tag.split('-');
+    let primary = parts.next().unwrap_or_default();
+    tag.len() <= 35
+        && (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && parts.all(|part| {
+            (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+/// the parts of `FrameConfig` an upload may change
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Layout {
+    enable: Option<bool>,
+    frame_color: Option<String>,
+    void_color: Option<String>,
+    fit: Option<Fit>,
+    image_size: Option<(u32, u32)>,
+}
+
+impl Layout {
+    /// values set in `other` win
+    fn merge(&mut self, other: Layout) {
+        self.enable = other.enable.or(self.enable);
+        self.frame_color = other.frame_color.or(self.frame_color.take());
+        self.void_color = other.void_color.or(self.void_color.take());
+        self.fit = other.fit.or(self.fit);
+        self.image_size = other.image_size.or(self.image_size);
+    }
+}
+
+/// the instance frame with the overrides it allows
+fn frame_layout(config: &Config, layout: Layout) -> Result<FrameConfig, UploadError> {
+    let mut frame = config.frame.clone();
+    let allowed = |field| {
+        frame
+            .overridable
+            .contains(&field)
+            .then_some(())
+            .ok_or(UploadError::BadRequest(
+                "layout field can't be changed on this instance",
+            ))
+    };
+
+    if let Some(enable) = layout.enable {
+        allowed(LayoutField::Enable)?;
+        frame.enable = enable;
+    }
+    if let Some(color) = layout.frame_color {
+        allowed(LayoutField::FrameColor)?;
+        if !valid_color(&color) {
+            return Err(UploadError::BadRequest("invalid frame_color"));
+        }
+        frame.frame_color = color;
+    }
+    if let Some(color) = layout.void_color {
+        allowed(LayoutField::VoidColor)?;
+        if !valid_color(&color) {
+            return Err(UploadError::BadRequest("invalid void_color"));
+        }
+        frame.void_color = color;
+    }
+    if let Some((width, height)) = layout.image_size {
+        allowed(LayoutField::ImageSize)?;
+        // the image has to fit inside the frame
+        let (max_width, max_height) = frame.frame_size;
+        if !(1..=max_width).contains(&width) || !(1..=max_height).contains(&height) {
+            return Err(UploadError::BadRequest("image_size doesn't fit the frame"));
+        }
+        frame.image_size = (width, height);
+    }
+    if let Some(fit) = layout.fit {
+        if fit != frame.fit && !frame.allowed_fits.contains(&fit) {
+            return Err(UploadError::BadRequest("invalid fit"));
+        }
+        frame.fit = fit;
+    }
+    Ok(frame)
+}
+
+/// form values taken from the audio tags
+#[derive(Serialize, Debug, Default)]
+struct Prefill {
+    title: Option<String>,
+    description: Option<String>,
+    tags: Vec<String>,
+}
+
+fn prefill(config: &Config, audio: &AudioTags) -> Prefill {
+    let title = audio
+        .render(&config.templates.title)
+        .or_else(|| audio.title.clone())
+        .filter(|title| title.len() <= MAX_TITLE);
+    let description = audio
+        .render(&config.templates.description)
+        .filter(|desc| desc.len() <= MAX_DESC - config.description_watermark.len() - 3);
+
+    let mut tags: Vec<String> = Vec::new();
+    let genres = audio
+        .genre
+        .iter()
+        .flat_map(|genre| genre.split([';', '/', ',']));
+    for tag in [audio.artist.as_deref(), audio.album.as_deref()]
+        .into_iter()
+        .flatten()
+        .chain(genres)
+    {
+        let tag = tag.trim();
+        if tag.is_empty() || tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+            continue;
+        }
+        // same limit as the tags field, with quotes around tags with spaces
+        let len: usize = tags
+            .iter()
+            .chain([&tag.to_owned()])
+            .map(|t| t.len() + 1 + if t.contains(' ') { 2 } else { 0 })
+            .sum();
+        if len >= 500 {
+            break;
+        }
+        tags.push(tag.to_owned());
+    }
+
+    Prefill {
+        title,
+        description,
+        tags,
+    }
+}
+
+async fn probe(
+    Extension(id): Extension<Ulid>,
+    State(AppState {
+        config, keypair, ..
+    }): State<AppState>,
+    cookies: CookieJar,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, UploadError> {
+    let claim_cookie = match cookies.get("token") {
+        Some(cookie) => cookie.value(),
+        None => return Err(UploadError::Unauthorized),
+    };
+    keypair
+        .public_key()
+        .verify_token::<TokenClaim>(claim_cookie, None)
+        .map_err(|_| UploadError::InvalidJWT("signature"))?;
+
+    while let Some(mut field) = multipart.next_field().await? {
+        if field.name() != Some("audio") {
+            continue;
+        }
+        let (_, path) = get_file_info(&field, config, id, "probe")?;
+        let mut file = File::create_new(&path).await?;
+        let probe = async {
+            take_upload(
+                &mut field,
+                &mut file,
+                "audio",
+                config.limits.upload.max_audio,
+            )
+            .await?;
+            let probe = probe_audio(&*path).await?;
+            audio_extension(config, &probe.format).ok_or_else(|| {
+                UploadError::UnsupportedFormat {
+                    subject: "audio",
+                    format: probe.format.clone(),
+                }
+            })?;
+            Ok::<_, UploadError>(probe)
+        }
+        .await;
+        drop(file);
+        if let Err(err) = tokio::fs::remove_file(&path).await {
+            error!("failed to remove probed file: {err}");
+        }
+        let probe = probe?;
+
+        return Ok(Json(json!({
+            "error": false,
+            "duration": probe.duration,
+            "codec": probe.codec,
+            "format": probe.format,
+            "tags": &probe.tags,
+            "prefill": prefill(config, &probe.tags),
+        })));
+    }
+    Err(UploadError::BadRequest("no audio file"))
+}
+
+/// embedded cover art, then the configured image, then a solid colour.
+/// the first one that passes `check_image` is used
+async fn default_cover(
+    config: &'static Config,
+    id: Ulid,
+    embedded: Option<(Arc<std::path::Path>, CoverStream)>,
+    size: (u32, u32),
+) -> Result<(Arc<std::path::Path>, (u32, u32)), UploadError> {
+    async fn checked(
+        config: &Config,
+        path: Arc<std::path::Path>,
+    ) -> Result<(Arc<std::path::Path>, (u32, u32)), UploadError> {
+        let fd = File::open(&path).await?;
+        let checked = check_image(config, path.clone(), fd).await;
+        if checked.is_err() {
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                error!("failed to remove rejected cover: {err}");
+            }
+        }
+        checked
+    }
+
+    if let Some((audio, cover)) = embedded {
+        match extract_cover(&audio, &cover, config, id).await {
+            Ok(path) => match checked(config, path).await {
+                Ok(image) => {
+                    debug!("using embedded cover");
+                    return Ok(image);
+                }
+                Err(err) => warn!("embedded cover rejected: {err}"),
+            },
+            Err(err) => warn!("failed to use embedded cover: {err}"),
+        }
+    }
+
+    let cover = &config.default_cover;
+    if let Some(image) = cover.image.as_ref() {
+        let extension = image.extension().unwrap_or_default().to_string_lossy();
+        let path: Arc<std::path::Path> = config
+            .temp_dir
+            .join(format!("image_{id}.{extension}"))
+            .into();
+        tokio::fs::copy(image, &path).await?;
+        match checked(config, path).await {
+            Ok(image) => return Ok(image),
+            Err(err) => warn!("default cover image rejected: {err}"),
+        }
+    }
+
+    let path: Arc<std::path::Path> = config.temp_dir.join(format!("image_{id}.png")).into();
+    let color = parse_hex_color(&cover.color).expect("checked on startup");
+    let image = path.clone();
+    spawn_blocking(move || solid_image(&image, size, color)).await??;
+    checked(config, path).await
+}
+
+fn check_duration(time: f64) -> Result<f64, UploadError> {
+    if !time.is_normal() && time != 0.0 {
+        return Err(UploadError::AudioMisc {
+            code: "invalid_duration",
+            message: "duration is not a valid number",
+        });
+    }
+    Ok(time)
+}
+
+/// renames the upload after the container ffprobe found, if it is allowed
+async fn check_audio(
+    config: &Config,
+    path: &std::path::Path,
+    format: &str,
+) -> Result<Arc<std::path::Path>, UploadError> {
+    let extension =
+        audio_extension(config, format).ok_or_else(|| UploadError::UnsupportedFormat {
+            subject: "audio",
+            format: format.to_owned(),
+        })?;
+    set_extension(path, extension).await
+}
+
+async fn check_image(
+    config: &Config,
+    path: Arc<std::path::Path>,
+    image_fd: File,
+) -> Result<(Arc<std::path::Path>, (u32, u32)), UploadError> {
+    let image_limits = config.limits.image;
+    let ImageSizeLimits {
+        min_resolution: (min_width, min_height),
+        max_resolution: (max_width, max_height),
+        max_decoded,
+    } = image_limits;
+    let image_fd = image_fd.into_std().await;
+    let (format, (width, height), decoded_size) =
+        spawn_blocking(move || decode_image(image_fd)).await??;
+
+    let extension = format.extensions_str()[0];
+    if !config
+        .formats
+        .image
+        .iter()
+        .any(|allowed| allowed == extension)
+    {
+        return Err(UploadError::UnsupportedFormat {
+            subject: "image",
+            format: extension.to_owned(),
+        });
+    }
+
+    #[allow(clippy::manual_range_contains)] // it is worse
+    if width < min_width || width > max_width || height < min_height || height > max_height {
+        Err(UploadError::ImageDimensions {
+            value: (width, height),
+            expected: image_limits,
+        })
+    } else if decoded_size > max_decoded {
+        Err(UploadError::FileTooLarge {
+            subject: "image_decoded",
+            max: max_decoded,
+        })
+    } else {
+        Ok((set_extension(&path, extension).await?, (width, height)))
+    }
+}
+
+/// `00:00 Track name` lines, youtube turns them into chapters too
+fn tracklist(chapters: &[Chapter]) -> String {
+    let hours = chapters.last().is_some_and(|chapter| chapter.end >= 3600.0);
+    let mut lines = Vec::with_capacity(chapters.len());
+    for chapter in chapters {
+        let s = chapter.start as u64;
+        lines.push(if hours {
+            format!(
+                "{}:{:02}:{:02} {}",
+                s / 3600,
+                s / 60 % 60,
+                s % 60,
+                chapter.title
+            )
+        } else {
+            format!("{:02}:{:02} {}", s / 60, s % 60, chapter.title)
+        });
+    }
+    lines.join("\n")
+}
+
+fn user_id(keypair: &Ed25519KeyPair, cookies: &CookieJar) -> Result<String, CancelError> {
+    let claim_cookie = match cookies.get("token") {
+        Some(cookie) => cookie.value(),
+        None => return Err(CancelError::Unauthorized),
+    };
+
+    let claims = keypair
+        .public_key()
+        .verify_token::<TokenClaim>(claim_cookie, None)
+        .map_err(|_| CancelError::InvalidJWT("signature"))?;
+    Ok(claims.custom.user_id)
+}
+
 pub async fn status(
-    State(AppState { job_tracker, .. }): State<AppState>,
+    State(AppState {
+        job_tracker,
+        job_queue,
+        job_store,
+        keypair,
+        ..
+    }): State<AppState>,
     Path(id): Path<Ulid>,
+    cookies: CookieJar,
     ws: WebSocketUpgrade,
 ) -> Result<impl IntoResponse, WsError> {
-    let job_entry = job_tracker.get_async(&id).await.ok_or(WsError::NoId(id))?;
-    let rx = StatusReceiver::clone(job_entry.get());
-    drop(job_entry);
+    let rx = job_tracker
+        .get_async(&id)
+        .await
+        .map(|job_entry| job_entry.get().status.subscribe());
+    if let Some(rx) = rx {
+        // only needed to cancel, watching is allowed without logging in
+        let user_id = user_id(&keypair, &cookies).ok();
+        return Ok(
+            ws.on_upgrade(move |socket| handle_ws(socket, id, rx, user_id, job_tracker, job_queue))
+        );
+    }
 
-    Ok(ws.on_upgrade(move |socket| handle_ws(socket, rx)))
+    // finished before a restart, only the last status is left
+    let stored = job_store.status(id)?.ok_or(WsError::NoId(id))?;
+    Ok(ws.on_upgrade(move |socket| handle_stored_ws(socket, stored.status)))
 }
 
-async fn handle_ws(mut socket: WebSocket, mut rx: StatusReceiver) {
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", tag = "action")]
+enum WsCommand {
+    Cancel,
+}
+
+async fn handle_ws(
+    mut socket: WebSocket,
+    id: Ulid,
+    mut rx: StatusReceiver,
+    user_id: Option<String>,
+    job_tracker: Arc<JobTracker>,
+    job_queue: Arc<JobQueue>,
+) {
     loop {
-        if rx.changed().await.is_err() {
-            debug!("channel closed");
-            break;
-        };
         // Sorry for this horror
         // The Ref<'_, T> type is weird
-        let json =
-            serde_json::to_string(&*rx.borrow_and_update()).expect("serialization should work");
-        if socket.send(Message::Text(json)).await.is_err() {
+        let (json, finished) = {
+            let status = rx.borrow_and_update();
+            (
+                serde_json::to_string(&*status).expect("serialization should work"),
+                status.is_finished(),
+            )
+        };
+        if socket.send(Message::Text(json)).await.is_err() || finished {
             break;
         };
+
+        let changed = loop {
+            select! {
+                v = rx.changed() => break v.is_ok(),
+                msg = socket.recv() => match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        let result = match serde_json::from_str(&text) {
+                            Ok(WsCommand::Cancel) => match user_id.as_deref() {
+                                Some(user_id) => {
+                                    cancel_job(&job_tracker, &job_queue, id, user_id).await
+                                }
+                                None => Err(CancelError::Unauthorized),
+                            },
+                            Err(_) => Err(CancelError::BadRequest("unknown command")),
+                        };
+                        if let Err(err) = result {
+                            debug!("websocket command failed: {err}");
+                            let json = err.to_json().1.to_string();
+                            if socket.send(Message::Text(json)).await.is_err() {
+                                break false;
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break false,
+                    Some(Ok(_)) => {}
+                }
+            }
+        };
+        if !changed {
+            debug!("channel closed");
+            break;
+        }
     }
 
     let _ = socket.close().await;
 }
 
+async fn cancel(
+    State(AppState {
+        job_tracker,
+        job_queue,
+        keypair,
+        ..
+    }): State<AppState>,
+    Path(id): Path<Ulid>,
+    cookies: CookieJar,
+) -> Result<impl IntoResponse, CancelError> {
+    let user_id = user_id(&keypair, &cookies)?;
+    cancel_job(&job_tracker, &job_queue, id, &user_id).await?;
+
+    Ok(Json(json!({
+        "error": false,
+        "id": id
+    })))
+}
+
+async fn handle_stored_ws(mut socket: WebSocket, status: serde_json::Value) {
+    let _ = socket.send(Message::Text(status.to_string())).await;
+    let _ = socket.close().await;
+}
+
 async fn limits(
     State(AppState {
-        config, job_sender, ..
+        config, job_queue, ..
     }): State<AppState>,
 ) -> Json<serde_json::Value> {
     Json(json!({
         "limits": config.limits,
-        "queue_slots": job_sender.capacity(),
+        "queue_slots": job_queue.capacity(),
+        "encoding_presets": config.encoding.allowed,
+        "fit": config.frame.fit,
+        "allowed_fits": config.frame.allowed_fits,
+        "layout": config.frame.overridable,
         "max_description": MAX_DESC - config.description_watermark.len() - 3
     }))
 }
@@ -396,7 +1357,9 @@ async fn limits(
 pub fn new(state: AppState) -> Router<()> {
     Router::new()
         .route("/upload", post(upload))
+        .route("/probe", post(probe))
         .route("/ws/:id", get(status))
+        .route("/jobs/:id", delete(cancel))
         .route("/oauth", get(crate::auth::oauth))
         .route("/oauth_prompt", get(crate::auth::oauth_prompt))
         .route("/limits", get(limits))
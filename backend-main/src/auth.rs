@@ -16,6 +16,7 @@ use tracing::{debug, error};
 use url::Url;
 
 use crate::app::AppState;
+use crate::config::OauthConfig;
 
 macro_rules! define_scopes {
     // https://users.rust-lang.org/t/how-to-create-a-string-from-macro-arguments-separated-by-commas/55121/2
@@ -25,7 +26,8 @@ macro_rules! define_scopes {
     };
 }
 
-define_scopes!["openid", "https://www.googleapis.com/auth/youtube.upload"];
+// the youtube scope comes from `auth.youtube_scope`
+define_scopes!["openid"];
 
 fn lazy_parse_jwt<T: DeserializeOwned>(a: &str) -> Option<T> {
     serde_json::from_str(
@@ -116,6 +118,50 @@ impl From<OauthRefreshResponseResult>
     }
 }
 
+#[derive(Error, Debug)]
+pub enum RefreshError {
+    #[error("failed to contact oauth servers: {0}")]
+    ReqwestError(#[from] reqwest::Error),
+    #[error("failed to parse refresh response")]
+    JsonParseError(#[from] serde_json::Error),
+    #[error(transparent)]
+    Rejected(#[from] OauthRefreshResponseError),
+}
+
+/// swaps in a new access token if the current one expires within `margin`,
+/// returns whether it did
+pub async fn refresh_token(
+    claim: &mut TokenClaim,
+    margin: Duration,
+    config: &OauthConfig,
+    client: &reqwest::Client,
+) -> Result<bool, RefreshError> {
+    // ideally do saturating_sub but `time` is kinda stupid
+    if OffsetDateTime::now_utc() <= claim.expires_at - margin {
+        return Ok(false);
+    }
+
+    let res = client
+        .post(config.token_uri.as_str())
+        .form(&[
+            ("grant_type", "refresh_token"),
+            ("client_id", &config.client_id),
+            ("client_secret", &config.client_secret),
+            ("refresh_token", &claim.refresh_token),
+        ])
+        .send()
+        .await?;
+    let bytes = res.bytes().await?;
+    let parsed: OauthRefreshResponseResult = serde_json::from_slice(&bytes)?;
+    drop(bytes);
+    let data = Result::from(parsed)?;
+
+    claim.access_token = data.access_token;
+    claim.expires_at = OffsetDateTime::now_utc()
+        + Duration::from_secs((data.expires_in as u64).saturating_sub(60));
+    Ok(true)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "lowercase")]
 pub struct OauthQuery {
@@ -227,6 +273,7 @@ pub async fn oauth(
 
     if SCOPES // of these scopes,
         .iter()
+        .chain([&config.auth.youtube_scope.url()])
         .any(
             |a| // if any
             !scope.iter().any(|b| a == b), // are not fouund in the vector
@@ -284,7 +331,10 @@ pub async fn oauth_prompt(State(AppState { config, .. }): State<AppState>) -> im
     query.append_pair("prompt", "consent");
     query.append_pair("include_granted_scopes", "false");
     query.append_pair("response_type", "code");
-    query.append_pair("scope", SCOPES_STR);
+    query.append_pair(
+        "scope",
+        &format!("{SCOPES_STR} {}", config.auth.youtube_scope.url()),
+    );
     query.append_pair("redirect_uri", redirect_url.as_str());
     query.append_pair("client_id", &config.auth.client_id);
     drop(query);
@@ -1,12 +1,15 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::fmt::Display;
 use std::net::{IpAddr, Ipv6Addr};
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use color_eyre::eyre::{bail, Context, Result};
 use rand::RngCore;
 use serde::de::Visitor;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::{error, info, instrument};
 use url::Url;
@@ -30,6 +33,55 @@ pub struct OauthConfig {
     pub client_id: String,
     pub client_secret: String,
     pub token_uri: Url,
+    /// youtube access requested from users
+    pub youtube_scope: YoutubeScope,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum YoutubeScope {
+    /// uploads and thumbnails only
+    #[default]
+    Upload,
+    /// full account access, needed for playlists
+    Youtube,
+    /// like `youtube`, also allows uploading captions
+    ForceSsl,
+}
+
+impl YoutubeScope {
+    pub fn url(self) -> &'static str {
+        match self {
+            Self::Upload => "https://www.googleapis.com/auth/youtube.upload",
+            Self::Youtube => "https://www.googleapis.com/auth/youtube",
+            Self::ForceSsl => "https://www.googleapis.com/auth/youtube.force-ssl",
+        }
+    }
+}
+
+/// resumable upload chunks have to be a multiple of this, except the last one
+pub const YOUTUBE_CHUNK_ALIGN: u64 = 256 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct YoutubeConfig {
+    /// base url of the google apis, can be pointed at a mock server
+    pub api_url: Url,
+    /// bytes sent per request of the resumable upload
+    pub chunk_size: u64,
+    /// milliseconds, first delay before retrying, doubled every attempt
+    pub retry_delay: u64,
+    /// milliseconds, total time an upload may spend retrying
+    pub retry_time: u64,
+    /// milliseconds without an answer before a request counts as dropped
+    pub timeout: u64,
+    /// bytes per second, chunks get `timeout` plus the time they'd take at this rate
+    pub min_upload_rate: u64,
+    /// set a thumbnail made from the cover after the upload,
+    /// only works on channels that are allowed custom thumbnails
+    pub thumbnail: bool,
+    /// create playlists that are asked for by name but don't exist yet
+    pub create_playlists: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -44,7 +96,10 @@ pub struct ImageSizeLimits {
 #[serde(default)]
 pub struct UploadLimits {
     pub max_image: u64,
+    /// per file, album tracks count separately
     pub max_audio: u64,
+    /// audio files in one album upload
+    pub max_tracks: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -62,6 +117,14 @@ pub struct ProcessingLimits {
     pub queue_size: usize,
     /// milliseconds
     pub time: u64,
+    /// milliseconds, replaces `time` for renders with a visualizer or burned lyrics
+    pub visualizer_time: u64,
+    /// jobs handled at the same time
+    pub workers: usize,
+    /// jobs allowed to run ffmpeg at the same time
+    pub render: usize,
+    /// jobs allowed to upload to youtube at the same time
+    pub upload: usize,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug, Clone, Copy)]
@@ -73,12 +136,226 @@ pub struct LimitsConfig {
     pub processing: ProcessingLimits,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug, Clone)]
-#[serde(rename_all = "lowercase")]
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
 pub enum Fit {
     #[default]
     Resize,
     Stretch,
+    /// fills `image_size` and crops what sticks out, keeping `focus` in view
+    Cover,
+    /// resized over a blurred and darkened copy of the image filling the frame
+    BlurFill,
+}
+
+#[derive(Error, Debug)]
+#[error("unrecognized fit value")]
+pub struct UnrecognizedFit;
+
+impl FromStr for Fit {
+    type Err = UnrecognizedFit;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resize" => Ok(Self::Resize),
+            "stretch" => Ok(Self::Stretch),
+            "cover" => Ok(Self::Cover),
+            "blur_fill" => Ok(Self::BlurFill),
+            _ => Err(UnrecognizedFit),
+        }
+    }
+}
+
+/// parts of the frame users may change per upload, the fit goes by `allowed_fits`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LayoutField {
+    Enable,
+    FrameColor,
+    VoidColor,
+    ImageSize,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TextAlign {
+    Left,
+    #[default]
+    Center,
+    Right,
+}
+
+/// text drawn on the frame
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TextLayer {
+    /// `{title}`, `{artist}` and the other audio tags, `{video_title}` for the video title.
+    /// lines with a missing value are left out, the layer too if nothing is left
+    pub text: String,
+    /// font file, fontconfig's default font if unset
+    pub font: Option<PathBuf>,
+    pub size: u32,
+    pub color: String,
+    /// border width, 0 for none
+    pub outline: u32,
+    pub outline_color: String,
+    /// shadow offset, 0 for none
+    pub shadow: u32,
+    pub shadow_color: String,
+    /// whether `x` is the start, middle or end of the text
+    pub align: TextAlign,
+    pub x: u32,
+    pub y: u32,
+    /// also draw it on videos without the frame, `x` and `y` are then on the cover
+    pub without_frame: bool,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+/// where and how `FrameConfig.watermark` is drawn
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WatermarkConfig {
+    pub anchor: Anchor,
+    /// distance from the anchored edges
+    pub margin: u32,
+    /// width as a fraction of the video width, native size if unset
+    pub scale: Option<f64>,
+    pub opacity: f64,
+    /// also draw it on videos without the frame
+    pub without_frame: bool,
+}
+
+/// background of `Fit::BlurFill`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BlurConfig {
+    /// gaussian blur strength, in pixels of the frame
+    pub sigma: f64,
+    /// multiplier for the blurred copy, 0 is black
+    pub brightness: f64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VisualizerKind {
+    /// a single still frame, by far the cheapest
+    #[default]
+    None,
+    Waves,
+    Spectrum,
+    Vectorscope,
+}
+
+/// animated audio visualization drawn over the image
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct VisualizerConfig {
+    pub kind: VisualizerKind,
+    /// position on the output
+    pub x: u32,
+    pub y: u32,
+    pub size: (u32, u32),
+    pub color: String,
+    /// 0.0 to 1.0
+    pub opacity: f64,
+    pub fps: u32,
+}
+
+/// accepted upload formats, detected from the file contents
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FormatConfig {
+    /// image extensions as named by the `image` crate (`png`, `jpg`, `webp`...)
+    pub image: Vec<String>,
+    /// ffprobe format names (`flac`, `mp3`, `mov`...)
+    pub audio: Vec<String>,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        Self {
+            image: ["png", "jpg", "webp", "bmp", "gif"]
+                .map(String::from)
+                .into(),
+            audio: ["flac", "mp3", "wav", "ogg", "matroska", "mov", "aiff", "wv"]
+                .map(String::from)
+                .into(),
+        }
+    }
+}
+
+/// cover for uploads without an image and without embedded art
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DefaultCoverConfig {
+    pub image: Option<PathBuf>,
+    /// `#rrggbb`, fills `frame.image_size` when there's no `image`
+    pub color: String,
+}
+
+impl Default for DefaultCoverConfig {
+    fn default() -> Self {
+        Self {
+            image: None,
+            color: "#000000".into(),
+        }
+    }
+}
+
+/// fill empty form fields from the audio tags.
+///
+/// `{title}`, `{artist}`, `{album}`, `{date}`, `{genre}`, `{isrc}` and
+/// `{comment}` are replaced, lines with a missing tag are left out
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TemplateConfig {
+    pub title: String,
+    pub description: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LyricsMode {
+    /// drawn into the video with the `subtitles` filter
+    #[default]
+    Burn,
+    /// uploaded as a caption track once the video exists
+    Caption,
+}
+
+/// synced lyrics uploaded next to the audio
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LyricsConfig {
+    pub mode: LyricsMode,
+    /// font family, looked up in `fonts_dir` before the system fonts
+    pub font: String,
+    pub fonts_dir: Option<PathBuf>,
+    pub font_size: u32,
+    /// ASS colours, `&HAABBGGRR`
+    pub primary_color: String,
+    pub outline_color: String,
+    pub outline: u32,
+    pub shadow: u32,
+    /// numpad layout, 2 is bottom center
+    pub alignment: u8,
+    /// distance from the edge of the video
+    pub margin: u32,
+    /// frame rate of burned lyrics when nothing else moves
+    pub fps: u32,
+    /// language and name of the caption track
+    pub language: String,
+    pub caption_name: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -86,6 +363,7 @@ pub enum Fit {
 pub struct FrameConfig {
     pub enable: bool,
     pub watermark: Option<PathBuf>,
+    pub watermark_style: WatermarkConfig,
     pub x: u32,
     pub y: u32,
     pub frame_size: (u32, u32),
@@ -93,6 +371,102 @@ pub struct FrameConfig {
     pub frame_color: String,
     pub void_color: String,
     pub fit: Fit,
+    /// modes users may pick per upload, besides `fit`
+    pub allowed_fits: Vec<Fit>,
+    /// fields users may override with `layout`
+    pub overridable: Vec<LayoutField>,
+    /// point of the image `Fit::Cover` keeps in view, 0..1 from the top left
+    pub focus: (f64, f64),
+    pub blur: BlurConfig,
+    pub text: Vec<TextLayer>,
+    pub visualizer: VisualizerConfig,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    #[default]
+    Matroska,
+    Mp4,
+}
+
+impl Container {
+    /// name of the ffmpeg muxer
+    pub fn format(self) -> &'static str {
+        match self {
+            Self::Matroska => "matroska",
+            Self::Mp4 => "mp4",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Matroska => "mkv",
+            Self::Mp4 => "mp4",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Matroska => "video/x-matroska",
+            Self::Mp4 => "video/mp4",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EncodingPreset {
+    pub codec: String,
+    /// takes priority over `bitrate`
+    pub crf: Option<u8>,
+    /// ffmpeg bitrate string, e.g. `"8M"`
+    pub bitrate: Option<String>,
+    pub pix_fmt: String,
+    /// e.g. `"stillimage"`
+    pub tune: Option<String>,
+    /// x264 preset, e.g. `"medium"`
+    pub preset: Option<String>,
+    pub container: Container,
+    /// audio codecs, as named by ffprobe, that are copied without re-encoding
+    pub audio_copy: Vec<String>,
+    /// codec for audio that can't be copied
+    pub audio_codec: String,
+    /// ignored by lossless codecs
+    pub audio_bitrate: Option<String>,
+}
+
+/// EBU R128 targets for the `loudnorm` filter
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct LoudnessConfig {
+    /// normalize uploads that don't say otherwise
+    pub normalize: bool,
+    /// integrated loudness, LUFS
+    pub integrated: f64,
+    /// maximum true peak, dBTP
+    pub true_peak: f64,
+    /// loudness range, LU
+    pub lra: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EncodingConfig {
+    /// preset used when the upload doesn't pick one
+    pub default: String,
+    /// presets users may pick per upload
+    pub allowed: Vec<String>,
+    pub presets: BTreeMap<String, EncodingPreset>,
+    pub loudness: LoudnessConfig,
+}
+
+impl EncodingConfig {
+    pub fn default_preset(&self) -> &EncodingPreset {
+        self.presets
+            .get(&self.default)
+            .expect("default preset is checked on startup")
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -148,9 +522,17 @@ pub struct Config {
     pub http: HttpConfig,
     //pub db_url: Url,
     pub auth: OauthConfig,
+    pub youtube: YoutubeConfig,
     pub limits: LimitsConfig,
     pub temp_dir: PathBuf,
+    /// directory of the on-disk job queue
+    pub job_store: PathBuf,
     pub frame: FrameConfig,
+    pub encoding: EncodingConfig,
+    pub lyrics: LyricsConfig,
+    pub templates: TemplateConfig,
+    pub default_cover: DefaultCoverConfig,
+    pub formats: FormatConfig,
     pub jwt_key: HexBytes<32>,
     pub description_watermark: String,
 }
@@ -164,9 +546,16 @@ impl Default for Config {
             http: Default::default(),
             //db_url: Url::parse("postgres:///musngr?host=%2Frun%2Fpostgresql&user=musngr").unwrap(),
             auth: Default::default(),
+            youtube: Default::default(),
             limits: Default::default(),
             temp_dir: PathBuf::from("temp"),
+            job_store: PathBuf::from("jobs"),
             frame: Default::default(),
+            encoding: Default::default(),
+            lyrics: Default::default(),
+            templates: Default::default(),
+            default_cover: Default::default(),
+            formats: Default::default(),
             jwt_key,
             description_watermark: String::new(),
         }
@@ -179,6 +568,22 @@ impl Default for OauthConfig {
             client_id: "YOUR-CLIENT-ID-HERE".into(),
             client_secret: "YOUR-CLIENT-SECRET-HERE".into(),
             token_uri: Url::parse("https://oauth2.googleapis.com/token").unwrap(),
+            youtube_scope: Default::default(),
+        }
+    }
+}
+
+impl Default for YoutubeConfig {
+    fn default() -> Self {
+        Self {
+            api_url: Url::parse("https://www.googleapis.com/").unwrap(),
+            chunk_size: 32 * YOUTUBE_CHUNK_ALIGN,
+            retry_delay: 1000,
+            retry_time: 600000,
+            timeout: 30000,
+            min_upload_rate: 64 * 1024,
+            thumbnail: true,
+            create_playlists: false,
         }
     }
 }
@@ -188,6 +593,7 @@ impl Default for FrameConfig {
         Self {
             enable: false,
             watermark: None,
+            watermark_style: Default::default(),
             x: 0,
             y: 0,
             frame_size: (1920, 1080),
@@ -195,6 +601,158 @@ impl Default for FrameConfig {
             frame_color: "black".into(),
             void_color: "black".into(),
             fit: Default::default(),
+            allowed_fits: Vec::new(),
+            overridable: Vec::new(),
+            focus: (0.5, 0.5),
+            blur: Default::default(),
+            text: Vec::new(),
+            visualizer: Default::default(),
+        }
+    }
+}
+
+impl Default for TemplateConfig {
+    fn default() -> Self {
+        Self {
+            title: "{artist} - {title}".into(),
+            description: "{artist} - {title}\nfrom {album} ({date})\nISRC: {isrc}".into(),
+        }
+    }
+}
+
+impl Default for LyricsConfig {
+    fn default() -> Self {
+        Self {
+            mode: Default::default(),
+            font: "Sans".into(),
+            fonts_dir: None,
+            font_size: 24,
+            primary_color: "&H00FFFFFF".into(),
+            outline_color: "&H00000000".into(),
+            outline: 2,
+            shadow: 0,
+            alignment: 2,
+            margin: 40,
+            fps: 10,
+            language: "en".into(),
+            caption_name: "Lyrics".into(),
+        }
+    }
+}
+
+impl Default for TextLayer {
+    fn default() -> Self {
+        Self {
+            text: "{video_title}".into(),
+            font: None,
+            size: 48,
+            color: "white".into(),
+            outline: 0,
+            outline_color: "black".into(),
+            shadow: 0,
+            shadow_color: "black@0.5".into(),
+            align: Default::default(),
+            x: 960,
+            y: 960,
+            without_frame: false,
+        }
+    }
+}
+
+impl Default for WatermarkConfig {
+    fn default() -> Self {
+        Self {
+            anchor: Default::default(),
+            margin: 0,
+            scale: None,
+            opacity: 1.0,
+            without_frame: false,
+        }
+    }
+}
+
+impl Default for BlurConfig {
+    fn default() -> Self {
+        Self {
+            sigma: 40.0,
+            brightness: 0.5,
+        }
+    }
+}
+
+impl Default for VisualizerConfig {
+    fn default() -> Self {
+        Self {
+            kind: Default::default(),
+            x: 0,
+            y: 880,
+            size: (1920, 200),
+            color: "white".into(),
+            opacity: 0.8,
+            fps: 30,
+        }
+    }
+}
+
+impl Default for EncodingPreset {
+    /// lossless 4:4:4, big but a perfect copy of the image
+    fn default() -> Self {
+        Self {
+            codec: "libx264".into(),
+            crf: Some(0),
+            bitrate: None,
+            pix_fmt: "yuv444p".into(),
+            tune: None,
+            preset: None,
+            container: Container::Matroska,
+            audio_copy: [
+                "flac",
+                "alac",
+                "opus",
+                "vorbis",
+                "aac",
+                "mp3",
+                "pcm_s16le",
+                "pcm_s24le",
+            ]
+            .map(String::from)
+            .into(),
+            audio_codec: "flac".into(),
+            audio_bitrate: None,
+        }
+    }
+}
+
+impl Default for EncodingConfig {
+    fn default() -> Self {
+        let standard = EncodingPreset {
+            crf: Some(18),
+            pix_fmt: "yuv420p".into(),
+            tune: Some("stillimage".into()),
+            preset: Some("medium".into()),
+            ..Default::default()
+        };
+
+        Self {
+            default: "lossless".into(),
+            allowed: vec!["lossless".into(), "standard".into()],
+            presets: BTreeMap::from([
+                ("lossless".into(), EncodingPreset::default()),
+                ("standard".into(), standard),
+            ]),
+            loudness: Default::default(),
+        }
+    }
+}
+
+impl Default for LoudnessConfig {
+    /// what youtube normalizes to
+    fn default() -> Self {
+        Self {
+            normalize: false,
+            integrated: -14.0,
+            true_peak: -1.0,
+            lra: 11.0,
         }
     }
 }
@@ -215,6 +773,10 @@ impl Default for ProcessingLimits {
         Self {
             queue_size: 10,
             time: 300000,
+            visualizer_time: 1800000,
+            workers: 2,
+            render: 1,
+            upload: 1,
         }
     }
 }
@@ -224,6 +786,7 @@ impl Default for UploadLimits {
         Self {
             max_image: 10_000_000,
             max_audio: 100_000_000,
+            max_tracks: 20,
         }
     }
 }
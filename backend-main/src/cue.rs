@@ -0,0 +1,240 @@
+use thiserror::Error;
+
+use crate::error::UploadError;
+use crate::ffmpeg::Chapter;
+
+/// cue sheet timestamps count 75 frames per second
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Error, Debug)]
+pub enum CueError {
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: &'static str },
+    #[error("cue sheet has no tracks")]
+    NoTracks,
+    #[error("cue sheet references more than one file")]
+    MultipleFiles,
+}
+
+#[derive(Debug, Default)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// seconds, from `INDEX 01`
+    pub start: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+/// first argument of a command, quoted or not
+fn argument(rest: &str) -> Option<String> {
+    let rest = rest.trim();
+    match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split_once('"').map(|(value, _)| value.to_owned()),
+        None => rest.split_whitespace().next().map(str::to_owned),
+    }
+}
+
+/// `mm:ss:ff` into seconds
+fn timestamp(value: &str) -> Option<f64> {
+    let mut parts = value.split(':').map(|part| part.parse::<u32>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || seconds >= 60 || frames >= 75 {
+        return None;
+    }
+    let seconds = minutes.checked_mul(60)?.checked_add(seconds)?;
+    Some(seconds as f64 + frames as f64 / FRAMES_PER_SECOND)
+}
+
+pub fn parse_cue(text: &str) -> Result<CueSheet, CueError> {
+    let mut sheet = CueSheet::default();
+    let mut files = 0;
+
+    for (i, line) in text.trim_start_matches('\u{feff}').lines().enumerate() {
+        let line_number = i + 1;
+        let syntax = |message| CueError::Syntax {
+            line: line_number,
+            message,
+        };
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                files += 1;
+                if files > 1 {
+                    return Err(CueError::MultipleFiles);
+                }
+            }
+            "TRACK" => {
+                let number = rest
+                    .split_whitespace()
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or(syntax("invalid track number"))?;
+                sheet.tracks.push(CueTrack {
+                    number,
+                    ..Default::default()
+                });
+            }
+            "TITLE" | "PERFORMER" => {
+                let value = argument(rest).ok_or(syntax("missing value"))?;
+                let is_title = command.eq_ignore_ascii_case("TITLE");
+                let target = match sheet.tracks.last_mut() {
+                    Some(track) if is_title => &mut track.title,
+                    Some(track) => &mut track.performer,
+                    None if is_title => &mut sheet.title,
+                    None => &mut sheet.performer,
+                };
+                *target = Some(value);
+            }
+            "INDEX" => {
+                let track = sheet
+                    .tracks
+                    .last_mut()
+                    .ok_or(syntax("INDEX outside of a track"))?;
+                let mut args = rest.split_whitespace();
+                let index: u32 = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or(syntax("invalid index number"))?;
+                let time = args
+                    .next()
+                    .and_then(timestamp)
+                    .ok_or(syntax("invalid index time"))?;
+                // index 00 is the pregap, the track starts at 01
+                if index == 1 {
+                    track.start = Some(time);
+                }
+            }
+            // REM, CATALOG, FLAGS, ISRC, SONGWRITER, PREGAP, POSTGAP...
+            _ => {}
+        }
+    }
+
+    if sheet.tracks.is_empty() {
+        return Err(CueError::NoTracks);
+    }
+    Ok(sheet)
+}
+
+impl CueSheet {
+    /// one chapter per track, the last one ends at `duration`
+    pub fn chapters(&self, duration: f64) -> Result<Vec<Chapter>, UploadError> {
+        let mut chapters: Vec<Chapter> = Vec::with_capacity(self.tracks.len());
+        let mut previous = None;
+        for (i, track) in self.tracks.iter().enumerate() {
+            let start = track.start.ok_or(UploadError::CueIndex {
+                track: track.number,
+                value: None,
+                duration,
+            })?;
+            if start >= duration || previous.is_some_and(|previous| start <= previous) {
+                return Err(UploadError::CueIndex {
+                    track: track.number,
+                    value: Some(start),
+                    duration,
+                });
+            }
+            if let Some(last) = chapters.last_mut() {
+                last.end = start;
+            }
+            previous = Some(start);
+
+            let title = track
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("Track {:02}", track.number));
+            let title = match &track.performer {
+                Some(performer) if self.performer.as_ref() != Some(performer) => {
+                    format!("{performer} - {title}")
+                }
+                _ => title,
+            };
+            chapters.push(Chapter {
+                title,
+                // anything before the first track belongs to it
+                start: if i == 0 { 0.0 } else { start },
+                end: duration,
+            });
+        }
+        Ok(chapters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sheet() {
+        let sheet = parse_cue(
+            "\u{feff}PERFORMER \"Artist\"
+TITLE \"Album\"
+FILE \"album.flac\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"First\"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Second\"
+    PERFORMER \"Guest\"
+    INDEX 00 03:58:00
+    INDEX 01 04:00:37
+",
+        )
+        .unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("Album"));
+        assert_eq!(sheet.performer.as_deref(), Some("Artist"));
+        assert_eq!(sheet.tracks.len(), 2);
+        assert_eq!(sheet.tracks[0].start, Some(0.0));
+        assert_eq!(sheet.tracks[1].start, Some(240.0 + 37.0 / 75.0));
+
+        let chapters = sheet.chapters(500.0).unwrap();
+        assert_eq!(chapters[0].title, "First");
+        assert_eq!(chapters[1].title, "Guest - Second");
+        assert_eq!(chapters[0].end, chapters[1].start);
+        assert_eq!(chapters[1].end, 500.0);
+    }
+
+    #[test]
+    fn rejects_out_of_range_times() {
+        for time in ["00:60:00", "00:00:75", "00:00", "00:00:00:00", "-1:00:00"] {
+            let err = parse_cue(&format!("TRACK 01 AUDIO\nINDEX 01 {time}")).unwrap_err();
+            assert!(
+                matches!(err, CueError::Syntax { line: 2, .. }),
+                "{time}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_minutes() {
+        let err = parse_cue("TRACK 01 AUDIO\nINDEX 01 99999999:00:00").unwrap_err();
+        assert!(matches!(err, CueError::Syntax { line: 2, .. }));
+        assert_eq!(timestamp("71582788:15:00"), Some(4294967295.0));
+        assert_eq!(timestamp("71582788:16:00"), None);
+    }
+
+    #[test]
+    fn rejects_multiple_files() {
+        let err = parse_cue(
+            "FILE \"a.flac\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\nFILE \"b.flac\" WAVE",
+        )
+        .unwrap_err();
+        assert!(matches!(err, CueError::MultipleFiles));
+    }
+
+    #[test]
+    fn rejects_sheets_without_tracks() {
+        assert!(matches!(
+            parse_cue("TITLE \"Album\"").unwrap_err(),
+            CueError::NoTracks
+        ));
+    }
+}
@@ -16,9 +16,12 @@ use tokio::task::JoinError;
 use tracing::{debug, error};
 use ulid::Ulid;
 
-use crate::auth::OauthRefreshResponseError;
+use crate::auth::{OauthRefreshResponseError, RefreshError};
 use crate::config::*;
+use crate::cue::CueError;
 use crate::ffprobe::FfprobeError;
+use crate::lyrics::LyricsError;
+use crate::store::StoreError;
 
 #[derive(Debug, Error)]
 pub enum UploadError {
@@ -38,6 +41,11 @@ pub enum UploadError {
     FileTooLarge { subject: &'static str, max: u64 },
     #[error("Invalid file name: {0:?}")]
     InvalidFileName(String),
+    #[error("Unsupported {subject} format: {format}")]
+    UnsupportedFormat {
+        subject: &'static str,
+        format: String,
+    },
     /*#[error("Content-Length too high (expected {expected}, got {value})")]
     ContentLength { expected: u64, value: u64 },*/
     #[error(transparent)]
@@ -65,6 +73,24 @@ pub enum UploadError {
         expected: AudioLengthLimits,
         value: f64,
     },
+    #[error("invalid cue sheet: {0}")]
+    CueParseError(#[from] CueError),
+    #[error("Cue sheet track {track} starts outside the audio (duration {duration})")]
+    CueIndex {
+        track: u32,
+        /// `None` when the track has no `INDEX 01`
+        value: Option<f64>,
+        duration: f64,
+    },
+    #[error("invalid lyrics: {0}")]
+    LyricsParseError(#[from] LyricsError),
+    #[error("Lyrics line {line} starts at {value} {reason} (audio length {duration})")]
+    LyricsTiming {
+        line: usize,
+        value: f64,
+        duration: f64,
+        reason: &'static str,
+    },
     #[error("Invalid token: {0}")]
     InvalidJWT(&'static str),
     #[error("failed to contact oauth servers: {0}")]
@@ -73,10 +99,22 @@ pub enum UploadError {
     RefreshError(#[from] OauthRefreshResponseError),
     #[error("failed to parse api response")]
     JsonParseError(#[from] serde_json::Error),
+    #[error(transparent)]
+    StoreError(#[from] StoreError),
     #[error("internal server error: {0}")]
     Other(Cow<'static, str>),
 }
 
+impl From<RefreshError> for UploadError {
+    fn from(value: RefreshError) -> Self {
+        match value {
+            RefreshError::ReqwestError(e) => Self::ReqwestError(e),
+            RefreshError::JsonParseError(e) => Self::JsonParseError(e),
+            RefreshError::Rejected(e) => Self::RefreshError(e),
+        }
+    }
+}
+
 impl IntoResponse for UploadError {
     fn into_response(self) -> Response {
         let message = self.to_string();
@@ -123,6 +161,16 @@ impl IntoResponse for UploadError {
                 })),
             )
                 .into_response(),
+            Self::UnsupportedFormat { subject, format } => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                Json(json!({
+                    "error": "unsupported_format",
+                    "subject": subject,
+                    "format": format,
+                    "message": message,
+                })),
+            )
+                .into_response(),
             Self::InvalidFileName(name) => (
                 StatusCode::BAD_REQUEST,
                 Json(json!({
@@ -181,6 +229,58 @@ impl IntoResponse for UploadError {
                 })),
             )
                 .into_response(),
+            Self::CueParseError(_) => (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "parse",
+                    "subject": "cue",
+                    "message": message,
+                })),
+            )
+                .into_response(),
+            Self::CueIndex {
+                track,
+                value,
+                duration,
+            } => (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "limits",
+                    "subject": "cue_index",
+                    "track": track,
+                    "value": value,
+                    "duration": duration,
+                    "message": message,
+                })),
+            )
+                .into_response(),
+            Self::LyricsParseError(_) => (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "parse",
+                    "subject": "lyrics",
+                    "message": message,
+                })),
+            )
+                .into_response(),
+            Self::LyricsTiming {
+                line,
+                value,
+                duration,
+                reason,
+            } => (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "limits",
+                    "subject": "lyrics_timing",
+                    "line": line,
+                    "value": value,
+                    "duration": duration,
+                    "reason": reason,
+                    "message": message,
+                })),
+            )
+                .into_response(),
             Self::ChannelClosed => (
                 StatusCode::SERVICE_UNAVAILABLE,
                 Json(json!({
@@ -202,6 +302,7 @@ impl IntoResponse for UploadError {
             | Self::JoinError(_)
             | Self::ReqwestError(_)
             | Self::JsonParseError(_)
+            | Self::StoreError(_)
             | Self::Other(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({
@@ -252,6 +353,8 @@ impl IntoResponse for UploadError {
 pub enum WsError {
     #[error("No job found with id {0}")]
     NoId(Ulid),
+    #[error(transparent)]
+    StoreError(#[from] StoreError),
 }
 
 impl IntoResponse for WsError {
@@ -268,7 +371,93 @@ impl IntoResponse for WsError {
                     "message": message,
                 })),
             ),
+            Self::StoreError(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "internal_error",
+                    "message": message,
+                })),
+            ),
         }
         .into_response()
     }
 }
+
+#[derive(Debug, Error)]
+pub enum CancelError {
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Invalid token: {0}")]
+    InvalidJWT(&'static str),
+    #[error("No job found with id {0}")]
+    NotFound(Ulid),
+    #[error("Job belongs to another user")]
+    Forbidden,
+    #[error("Job already finished")]
+    Finished,
+    #[error("Bad Request: {0}")]
+    BadRequest(&'static str),
+}
+
+impl CancelError {
+    /// shared between the http endpoint and the websocket
+    pub fn to_json(&self) -> (StatusCode, serde_json::Value) {
+        let message = self.to_string();
+
+        match self {
+            Self::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                json!({
+                    "error": "unauthorized",
+                    "message": message,
+                }),
+            ),
+            Self::InvalidJWT(reason) => (
+                StatusCode::UNAUTHORIZED,
+                json!({
+                    "error": "invalid_jwt",
+                    "reason": reason,
+                    "message": message,
+                }),
+            ),
+            Self::NotFound(id) => (
+                StatusCode::NOT_FOUND,
+                json!({
+                    "error": "not_found",
+                    "id": id,
+                    "message": message,
+                }),
+            ),
+            Self::Forbidden => (
+                StatusCode::FORBIDDEN,
+                json!({
+                    "error": "forbidden",
+                    "message": message,
+                }),
+            ),
+            Self::Finished => (
+                StatusCode::CONFLICT,
+                json!({
+                    "error": "finished",
+                    "message": message,
+                }),
+            ),
+            Self::BadRequest(_) => (
+                StatusCode::BAD_REQUEST,
+                json!({
+                    "error": "bad_request",
+                    "message": message,
+                }),
+            ),
+        }
+    }
+}
+
+impl IntoResponse for CancelError {
+    fn into_response(self) -> Response {
+        error!("error cancelling job: {self}");
+
+        let (status, body) = self.to_json();
+        (status, Json(body)).into_response()
+    }
+}
@@ -1,56 +1,267 @@
 use std::borrow::Cow;
+use std::collections::{BTreeMap, VecDeque};
 use std::io::SeekFrom;
+use std::mem::discriminant;
 use std::path::Path;
-use std::process::ExitStatus;
+use std::process::{ExitStatus, Stdio};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use image::{image_dimensions, ImageError};
-use reqwest::header;
+use futures_util::TryStreamExt;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{overlay, FilterType};
+use image::{ImageError, ImageReader, RgbImage};
+use rand::Rng;
+use reqwest::{header, StatusCode};
 use serde::{Deserialize, Serialize, Serializer};
 use serde_json::json;
 use thiserror::Error;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 use tokio::fs::File;
-use tokio::io::AsyncSeekExt;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, BufReader};
 use tokio::process::Command;
 use tokio::select;
-use tokio::sync::watch::error::SendError;
-use tokio::sync::{mpsc, watch};
+use tokio::sync::{watch, Notify, Semaphore, SemaphorePermit, TryAcquireError};
 use tokio::task::{spawn_blocking, JoinError};
 use tokio::time::{sleep, timeout};
 use tokio_util::io::ReaderStream;
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
-use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 use ulid::Ulid;
 
-use crate::auth::TokenClaim;
-use crate::config::{Fit, FrameConfig, LimitsConfig};
+use crate::auth::{refresh_token, RefreshError, TokenClaim};
+use crate::config::{
+    Anchor, Config, Container, EncodingPreset, Fit, FrameConfig, LimitsConfig, LoudnessConfig,
+    LyricsConfig, LyricsMode, TextAlign, TextLayer, VisualizerKind, YoutubeConfig,
+};
+use crate::error::CancelError;
+use crate::filtergraph::{Filter, Graph, Pad, Size};
+use crate::store::{JobStore, StoreError, UnfinishedJob};
 
-pub type JobSender = mpsc::Sender<QueuedJobInfo>;
-pub type JobReceiver = mpsc::Receiver<QueuedJobInfo>;
 pub type StatusSender = watch::Sender<StatusUpdate>;
 pub type StatusReceiver = watch::Receiver<StatusUpdate>;
-pub type JobTracker = scc::HashMap<Ulid, StatusReceiver>;
+pub type JobTracker = scc::HashMap<Ulid, TrackedJob>;
 
-#[derive(Debug)]
+/// how long a finished job's status stays available
+pub const JOB_EXPIRY: Duration = Duration::from_secs(15 * 60);
+/// the access token has to last this long into the upload and the steps after it
+const TOKEN_MARGIN: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct JobInfo {
     pub id: Ulid,
     pub frame: Arc<FrameConfig>,
     pub limits: Arc<LimitsConfig>,
+    pub encoding: Arc<EncodingPreset>,
     pub image_path: Arc<Path>,
     pub image_size: (u32, u32),
     pub audio_path: Arc<Path>,
     pub output_path: Arc<Path>,
     pub audio_length: f64,
+    pub audio: AudioMode,
+    /// two-pass loudness normalization towards these targets
+    pub normalize: Option<LoudnessConfig>,
+    /// album tracks in order, joined into `audio_path` before rendering
+    #[serde(default)]
+    pub tracks: Vec<Track>,
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+    #[serde(default)]
+    pub lyrics: Option<Lyrics>,
+    /// uploaded thumbnail, the cover is used without one
+    #[serde(default)]
+    pub thumbnail: Option<Arc<Path>>,
+    #[serde(default)]
+    pub playlists: Vec<PlaylistTarget>,
     pub meta: Metadata,
     pub auth: TokenClaim,
 }
 
+/// one song of an album upload
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Track {
+    pub title: String,
+    pub audio_path: Arc<Path>,
+    /// seconds
+    pub length: f64,
+    /// shown instead of the album image while the track plays
+    pub cover: Option<TrackCover>,
+}
+
+/// synced lyrics, already converted to srt
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Lyrics {
+    pub path: Arc<Path>,
+    pub mode: LyricsMode,
+    pub config: Arc<LyricsConfig>,
+}
+
+/// playlist the video is added to after the upload
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaylistTarget {
+    /// playlist id or title, `name#3` inserts at position 3 (zero based)
+    pub name: String,
+}
+
+impl PlaylistTarget {
+    /// the name without a trailing `#N` and the position it asks for
+    pub fn positioned(&self) -> Option<(&str, u32)> {
+        let (name, position) = self.name.rsplit_once('#')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        Some((name, position.parse().ok()?))
+    }
+}
+
+/// a named section of the output, written as a container chapter
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chapter {
+    pub title: String,
+    /// seconds
+    pub start: f64,
+    pub end: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TrackCover {
+    pub path: Arc<Path>,
+    pub size: (u32, u32),
+}
+
 pub struct QueuedJobInfo {
-    info: JobInfo,
-    tx: watch::Sender<StatusUpdate>,
+    info: Arc<JobInfo>,
+    status: StatusHandle,
+    cancel: CancellationToken,
+}
+
+/// jobs waiting for a worker, cancelled ones are taken out right away
+pub struct JobQueue {
+    jobs: Mutex<VecDeque<QueuedJobInfo>>,
+    /// one permit per free place, closed once the workers are gone
+    slots: Semaphore,
+    pushed: Notify,
+}
+
+/// a reserved place in the queue, given back if it's dropped unused
+pub struct QueueSlot<'a> {
+    queue: &'a JobQueue,
+    permit: SemaphorePermit<'a>,
+}
+
+impl QueueSlot<'_> {
+    pub fn send(self, job: QueuedJobInfo) {
+        // the place is given back when the job leaves the queue
+        self.permit.forget();
+        self.queue.jobs.lock().unwrap().push_back(job);
+        self.queue.pushed.notify_one();
+    }
+}
+
+impl JobQueue {
+    pub fn new(size: usize) -> Self {
+        Self {
+            jobs: Mutex::default(),
+            slots: Semaphore::new(size),
+            pushed: Notify::new(),
+        }
+    }
+
+    pub fn try_reserve(&self) -> Result<QueueSlot<'_>, TryAcquireError> {
+        Ok(QueueSlot {
+            queue: self,
+            permit: self.slots.try_acquire()?,
+        })
+    }
+
+    /// free places
+    pub fn capacity(&self) -> usize {
+        self.slots.available_permits()
+    }
+
+    /// waits for the next job
+    async fn pop(&self) -> QueuedJobInfo {
+        loop {
+            if let Some(job) = self.jobs.lock().unwrap().pop_front() {
+                self.slots.add_permits(1);
+                return job;
+            }
+            self.pushed.notified().await;
+        }
+    }
+
+    /// takes a job out before it reaches a worker
+    fn remove(&self, id: Ulid) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(index) = jobs.iter().position(|job| job.info.id == id) else {
+            return false;
+        };
+        jobs.remove(index);
+        drop(jobs);
+        self.slots.add_permits(1);
+        true
+    }
+
+    /// fails new reservations with `TryAcquireError::Closed`
+    fn close(&self) {
+        self.slots.close();
+    }
+}
+
+pub struct TrackedJob {
+    pub info: Arc<JobInfo>,
+    pub status: StatusHandle,
+    pub cancel: CancellationToken,
+}
+
+/// sends status updates to the websocket and keeps the job store in sync
+#[derive(Clone)]
+pub struct StatusHandle {
+    id: Ulid,
+    tx: StatusSender,
+    store: JobStore,
+}
+
+impl StatusHandle {
+    fn new(id: Ulid, store: JobStore) -> Self {
+        Self {
+            id,
+            tx: watch::Sender::new(Default::default()),
+            store,
+        }
+    }
+
+    pub fn subscribe(&self) -> StatusReceiver {
+        self.tx.subscribe()
+    }
+
+    /// updates are ignored once the job is in a final state
+    fn send(&self, status: StatusUpdate) {
+        self.transition(|current| !current.is_finished(), status);
+    }
+
+    fn transition(&self, from: impl FnOnce(&StatusUpdate) -> bool, to: StatusUpdate) -> bool {
+        let mut to = Some(to);
+        self.tx.send_if_modified(|current| {
+            if !from(current) {
+                return false;
+            }
+            let to = to.take().expect("only taken once");
+            // progress updates only live in memory
+            let persist = discriminant(current) != discriminant(&to);
+            *current = to;
+            if persist {
+                if let Err(err) = self.store.set_status(self.id, current) {
+                    error!("failed to persist job status: {err}");
+                }
+            }
+            true
+        })
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -79,7 +290,32 @@ pub enum UploadResponseResult {
     Err(GoogleErrorResponse),
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+/// what happens to the audio stream while rendering
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "mode")]
+pub enum AudioMode {
+    Copy { codec: String },
+    Transcode { from: String, to: String },
+}
+
+impl AudioMode {
+    pub fn choose(codec: &str, preset: &EncodingPreset, normalize: bool) -> Self {
+        // filtered audio can't be copied
+        if !normalize && preset.audio_copy.iter().any(|c| c == codec) {
+            Self::Copy {
+                codec: codec.to_owned(),
+            }
+        } else {
+            Self::Transcode {
+                from: codec.to_owned(),
+                to: preset.audio_codec.clone(),
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 #[repr(u8)]
 pub enum Privacy {
     Private,
@@ -115,7 +351,7 @@ impl FromStr for Privacy {
     }
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Metadata {
     pub filename: String,
     pub title: Option<String>,
@@ -125,6 +361,55 @@ pub struct Metadata {
     pub category: u32,
     pub made_for_kids: bool,
     pub notify_subs: bool,
+    /// the video stays private until then
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub publish_at: Option<OffsetDateTime>,
+    /// BCP-47 language of the title and description
+    #[serde(default)]
+    pub default_language: Option<String>,
+    #[serde(default)]
+    pub default_audio_language: Option<String>,
+    #[serde(default)]
+    pub license: Option<License>,
+    #[serde(default)]
+    pub embeddable: Option<bool>,
+    #[serde(default)]
+    pub public_stats_viewable: Option<bool>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub recording_date: Option<OffsetDateTime>,
+    /// titles and descriptions by language
+    #[serde(default)]
+    pub localizations: BTreeMap<String, Localization>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum License {
+    Youtube,
+    CreativeCommon,
+}
+
+#[derive(Error, Debug)]
+#[error("unrecognized license value")]
+pub struct UnrecognizedLicense;
+
+impl FromStr for License {
+    type Err = UnrecognizedLicense;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "youtube" => Ok(Self::Youtube),
+            "creativeCommon" => Ok(Self::CreativeCommon),
+            _ => Err(UnrecognizedLicense),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Localization {
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
 }
 
 pub const CATEGORY_IDS: &[u32] = &[
@@ -149,10 +434,28 @@ pub enum YTUploadError {
     JsonError(#[from] serde_json::Error),
     #[error("failed to upload video: {0}")]
     UploadError(#[from] GoogleErrorResponse),
+    #[error("gave up retrying the upload after {0:?}")]
+    RetriesExhausted(Duration),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
     #[error("internal server error: {0}")]
     Other(Cow<'static, str>),
 }
 
+#[derive(Error, Debug)]
+pub enum ThumbnailError {
+    #[error(transparent)]
+    ImageError(#[from] ImageError),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    JoinError(#[from] JoinError),
+    #[error("thumbnail is still too large at the lowest quality")]
+    TooLarge,
+    #[error(transparent)]
+    UploadError(#[from] YTUploadError),
+}
+
 #[derive(Error, Debug)]
 pub enum FFmpegProcessError {
     #[error("video took too long to process (took {value:?}, max {max:?})")]
@@ -167,6 +470,8 @@ pub enum FFmpegProcessError {
     JoinError(#[from] JoinError),
     #[error("ffmpeg error: {0}")]
     FfmpegError(ExitStatus),
+    #[error("failed to read loudness analysis: {0}")]
+    LoudnessParseError(Cow<'static, str>),
 }
 
 #[derive(Error, Debug)]
@@ -175,10 +480,35 @@ pub enum VideoProcessError {
     YTUpload(#[from] YTUploadError),
     #[error("processing error: {0}")]
     FFmpegProcessError(#[from] FFmpegProcessError),
-    #[error("channel closed")]
-    ChannelClosed(#[from] SendError<StatusUpdate>),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
+    #[error("couldn't refresh the access token: {0}")]
+    Refresh(#[from] RefreshError),
+    /// a restart stopped the job where it can't be picked up again
+    #[error("interrupted by a restart, {0}")]
+    Interrupted(&'static str),
+}
+
+/// integrated loudness analysis of the input, from the first `loudnorm` pass
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct LoudnessMeasurement {
+    /// LUFS
+    pub integrated: f64,
+    /// dBTP
+    pub true_peak: f64,
+    /// LU
+    pub lra: f64,
+    pub threshold: f64,
+    pub offset: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct JobOutput {
+    pub video_id: String,
+    pub loudness: Option<LoudnessMeasurement>,
+    /// steps after the upload that failed without failing the job
+    pub warnings: Vec<String>,
+    pub publish_at: Option<OffsetDateTime>,
 }
 
 #[derive(Serialize, Debug, Clone, Default)]
@@ -186,99 +516,138 @@ pub enum VideoProcessError {
 pub enum StatusUpdate {
     #[default]
     Queued,
-    Processing,
-    Uploading,
+    Processing {
+        percent: f64,
+        speed: Option<f64>,
+        eta_secs: Option<f64>,
+        audio: AudioMode,
+    },
+    Uploading {
+        sent: u64,
+        total: u64,
+        /// bytes per second since the last update, missing when that was too recent to tell
+        rate: Option<f64>,
+    },
     #[serde(serialize_with = "done_value")]
-    Done(Result<String, Arc<VideoProcessError>>),
+    Done(Result<JobOutput, Arc<VideoProcessError>>),
+    Cancelled,
 }
 
-fn done_value<S: Serializer>(
-    value: &Result<String, Arc<VideoProcessError>>,
-    serializer: S,
-) -> Result<S::Ok, S::Error> {
-    impl From<&VideoProcessError> for serde_json::Value {
-        fn from(value: &VideoProcessError) -> Self {
-            let message = value.to_string();
-
-            let mut map = match value {
-                VideoProcessError::YTUpload(YTUploadError::ReqwestError(_)) => json!({
-                    "error": "reqwest",
-                    "stage": "upload",
-                }),
-                VideoProcessError::YTUpload(YTUploadError::UploadError(v)) => json!({
-                    "error": "api",
-                    "stage": "upload",
-                    "original": v.error
-                }),
-                VideoProcessError::YTUpload(YTUploadError::JsonError(_)) => json!({
-                    "error": "parse",
-                    "stage": "upload",
-                }),
-                VideoProcessError::YTUpload(YTUploadError::Other(_)) => json!({
-                    "error": "other",
-                    "stage": "upload",
-                }),
-                VideoProcessError::FFmpegProcessError(FFmpegProcessError::TimeLimitExceeded {
-                    value,
-                    max,
-                }) => {
-                    json!({
-                        "error": "timeout",
-                        "stage": "video",
-                        "value": value.as_millis_f64(),
-                        "max": max.as_millis_f64()
-                    })
-                }
-                VideoProcessError::FFmpegProcessError(FFmpegProcessError::SpawnError(_)) => {
-                    json!({
-                        "error": "spawn",
-                        "stage": "video",
-                    })
-                }
-                VideoProcessError::FFmpegProcessError(FFmpegProcessError::IoError(_)) => json!({
-                    "error": "io",
-                    "stage": "video",
-                }),
-                VideoProcessError::FFmpegProcessError(FFmpegProcessError::ImageParseError(_)) => {
-                    json!({
-                        "error": "image_parse",
-                        "stage": "video",
-                    })
-                }
-                VideoProcessError::FFmpegProcessError(FFmpegProcessError::JoinError(_)) => {
-                    json!({
-                        "error": "join",
-                        "stage": "video",
-                    })
-                }
-                VideoProcessError::FFmpegProcessError(FFmpegProcessError::FfmpegError(_)) => {
-                    json!({
-                        "error": "ffmpeg",
-                        "stage": "video",
-                    })
-                }
-                VideoProcessError::ChannelClosed(_) => json!({
-                    "error": "channel",
-                    "stage": "internal",
-                }),
-                VideoProcessError::IoError(_) => json!({
-                    "error": "io",
-                    "stage": "internal"
-                }),
-            };
-            match &mut map {
-                Self::Object(map) => map.insert("message".into(), Self::String(message)),
-                _ => unreachable!("all variants result in a map"),
-            };
+impl StatusUpdate {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Done(_) | Self::Cancelled)
+    }
+}
 
-            map
-        }
+impl From<&VideoProcessError> for serde_json::Value {
+    fn from(value: &VideoProcessError) -> Self {
+        let message = value.to_string();
+
+        let mut map = match value {
+            VideoProcessError::YTUpload(YTUploadError::ReqwestError(_)) => json!({
+                "error": "reqwest",
+                "stage": "upload",
+            }),
+            VideoProcessError::YTUpload(YTUploadError::UploadError(v)) => json!({
+                "error": "api",
+                "stage": "upload",
+                "original": v.error
+            }),
+            VideoProcessError::YTUpload(YTUploadError::JsonError(_)) => json!({
+                "error": "parse",
+                "stage": "upload",
+            }),
+            VideoProcessError::YTUpload(YTUploadError::RetriesExhausted(value)) => json!({
+                "error": "retries",
+                "stage": "upload",
+                "value": value.as_millis_f64(),
+            }),
+            VideoProcessError::YTUpload(YTUploadError::IoError(_)) => json!({
+                "error": "io",
+                "stage": "upload",
+            }),
+            VideoProcessError::YTUpload(YTUploadError::Other(_)) => json!({
+                "error": "other",
+                "stage": "upload",
+            }),
+            VideoProcessError::FFmpegProcessError(FFmpegProcessError::TimeLimitExceeded {
+                value,
+                max,
+            }) => {
+                json!({
+                    "error": "timeout",
+                    "stage": "video",
+                    "value": value.as_millis_f64(),
+                    "max": max.as_millis_f64()
+                })
+            }
+            VideoProcessError::FFmpegProcessError(FFmpegProcessError::SpawnError(_)) => {
+                json!({
+                    "error": "spawn",
+                    "stage": "video",
+                })
+            }
+            VideoProcessError::FFmpegProcessError(FFmpegProcessError::IoError(_)) => json!({
+                "error": "io",
+                "stage": "video",
+            }),
+            VideoProcessError::FFmpegProcessError(FFmpegProcessError::ImageParseError(_)) => {
+                json!({
+                    "error": "image_parse",
+                    "stage": "video",
+                })
+            }
+            VideoProcessError::FFmpegProcessError(FFmpegProcessError::JoinError(_)) => {
+                json!({
+                    "error": "join",
+                    "stage": "video",
+                })
+            }
+            VideoProcessError::FFmpegProcessError(FFmpegProcessError::FfmpegError(_)) => {
+                json!({
+                    "error": "ffmpeg",
+                    "stage": "video",
+                })
+            }
+            VideoProcessError::FFmpegProcessError(FFmpegProcessError::LoudnessParseError(_)) => {
+                json!({
+                    "error": "loudness",
+                    "stage": "video",
+                })
+            }
+            VideoProcessError::IoError(_) => json!({
+                "error": "io",
+                "stage": "internal"
+            }),
+            VideoProcessError::Refresh(_) => json!({
+                "error": "refresh",
+                "stage": "auth"
+            }),
+            VideoProcessError::Interrupted(_) => json!({
+                "error": "interrupted",
+                "stage": "internal"
+            }),
+        };
+        match &mut map {
+            Self::Object(map) => map.insert("message".into(), Self::String(message)),
+            _ => unreachable!("all variants result in a map"),
+        };
+
+        map
     }
+}
 
+fn done_value<S: Serializer>(
+    value: &Result<JobOutput, Arc<VideoProcessError>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
     let done = match value {
-        Ok(id) => json!({
+        Ok(output) => json!({
             "success": true,
-            "video_id": id.clone(),
+            "video_id": &output.video_id,
+            "loudness": output.loudness,
+            "warnings": &output.warnings,
+            "publish_at": output.publish_at.and_then(|at| at.format(&Rfc3339).ok()),
         }),
         Err(err) => {
             let err: &VideoProcessError = err;
@@ -294,136 +663,1004 @@ fn done_value<S: Serializer>(
 
 pub async fn submit_job(
     info: JobInfo,
-    permit: mpsc::Permit<'_, QueuedJobInfo>,
+    slot: QueueSlot<'_>,
     job_tracker: &JobTracker,
-) {
-    let id = info.id;
+    job_store: &JobStore,
+) -> Result<(), StoreError> {
+    job_store.insert(&info)?;
+    let job = track_job(Arc::new(info), job_store.clone(), job_tracker).await;
+    slot.send(job);
+    Ok(())
+}
+
+async fn track_job(
+    info: Arc<JobInfo>,
+    job_store: JobStore,
+    job_tracker: &JobTracker,
+) -> QueuedJobInfo {
+    let status = StatusHandle::new(info.id, job_store);
+    let cancel = CancellationToken::new();
+
+    job_tracker
+        .upsert_async(
+            info.id,
+            TrackedJob {
+                info: info.clone(),
+                status: status.clone(),
+                cancel: cancel.clone(),
+            },
+        )
+        .await;
+    QueuedJobInfo {
+        info,
+        status,
+        cancel,
+    }
+}
+
+/// cancels a job owned by `user_id`.
+///
+/// running jobs are stopped by their worker, queued ones are finished here
+/// and taken out of the queue so they don't hold on to a place in it.
+pub async fn cancel_job(
+    job_tracker: &Arc<JobTracker>,
+    job_queue: &JobQueue,
+    id: Ulid,
+    user_id: &str,
+) -> Result<(), CancelError> {
+    let entry = job_tracker
+        .get_async(&id)
+        .await
+        .ok_or(CancelError::NotFound(id))?;
+    let TrackedJob {
+        info,
+        status,
+        cancel,
+    } = entry.get();
+    if info.auth.user_id != user_id {
+        return Err(CancelError::Forbidden);
+    }
+    if status.tx.borrow().is_finished() {
+        return Err(CancelError::Finished);
+    }
+    let (info, status) = (info.clone(), status.clone());
+    cancel.cancel();
+    drop(entry);
+
+    info!(%id, "cancelling job");
+    if status.transition(
+        |current| matches!(current, StatusUpdate::Queued),
+        StatusUpdate::Cancelled,
+    ) {
+        cleanup_job(&info).await;
+        // restored jobs aren't in the queue, the worker expires those when it skips them
+        if job_queue.remove(id) {
+            // shutting down drops this before it fires, keeping the stored status
+            tokio::spawn(expire_job(
+                id,
+                JOB_EXPIRY,
+                job_tracker.clone(),
+                status.store.clone(),
+                CancellationToken::new(),
+            ));
+        }
+    }
+    Ok(())
+}
 
-    let (tx, rx) = watch::channel(Default::default());
+/// jobs recovered from the store on startup
+pub struct RestoredJobs {
+    queued: Vec<QueuedJobInfo>,
+    finished: Vec<(Ulid, Duration)>,
+}
+
+/// puts jobs that were queued or interrupted by a restart back in the tracker,
+/// fails the ones that were already uploading, and works out how long finished
+/// ones should stay around
+pub async fn restore_jobs(
+    job_store: &JobStore,
+    job_tracker: &JobTracker,
+    temp_dir: &Path,
+) -> Result<RestoredJobs, StoreError> {
+    let mut queued = Vec::new();
+    for UnfinishedJob { id, info, status } in job_store.unfinished()? {
+        let failed =
+            |reason| StatusUpdate::Done(Err(Arc::new(VideoProcessError::Interrupted(reason))));
+        let info = match info {
+            Ok(info) => info,
+            Err(err) => {
+                warn!(%id, "can't restore job: {err}");
+                match remove_temp_files(temp_dir, id).await {
+                    Ok(removed) => debug!(%id, removed, "removed files of unreadable job"),
+                    Err(err) => warn!(%id, "failed to remove files of unreadable job: {err}"),
+                }
+                job_store.set_status(id, &failed("its stored copy can't be read"))?;
+                continue;
+            }
+        };
+        // starting over could publish the video a second time
+        if status.is_some_and(|status| status.status["state"] == "uploading") {
+            warn!(%id, "job was interrupted while uploading, not restarting it");
+            cleanup_job(&info).await;
+            job_store.set_status(id, &failed("the upload might have gone through"))?;
+            continue;
+        }
+        // an interrupted render leaves a partial output behind
+        if tokio::fs::remove_file(&info.output_path).await.is_ok() {
+            debug!(%id, "removed partial output");
+        }
+        job_store.set_status(id, &StatusUpdate::Queued)?;
+        queued.push(track_job(Arc::new(info), job_store.clone(), job_tracker).await);
+    }
+    queued.sort_by_key(|job| job.info.id);
+
+    let now = OffsetDateTime::now_utc();
+    let mut finished = Vec::new();
+    for (id, finished_at) in job_store.finished()? {
+        let remaining =
+            JOB_EXPIRY.saturating_sub((now - finished_at).try_into().unwrap_or_default());
+        if remaining.is_zero() {
+            job_store.remove(id)?;
+        } else {
+            finished.push((id, remaining));
+        }
+    }
+
+    info!(
+        queued = queued.len(),
+        finished = finished.len(),
+        "restored jobs"
+    );
+    Ok(RestoredJobs { queued, finished })
+}
 
-    job_tracker.upsert_async(id, rx).await;
-    permit.send(QueuedJobInfo { info, tx });
+/// state accumulated from ffmpeg's `-progress` output
+#[derive(Debug, Default)]
+struct FfmpegProgress {
+    frame: u64,
+    /// seconds of output written
+    out_time: f64,
+    speed: Option<f64>,
 }
 
-async fn run_ffmpeg(job: Arc<JobInfo>, output_path: Arc<Path>) -> Result<File, FFmpegProcessError> {
+impl FfmpegProgress {
+    /// feeds one `key=value` line, returns true once a full block was read
+    fn feed(&mut self, line: &str) -> bool {
+        let Some((key, value)) = line.split_once('=') else {
+            return false;
+        };
+        let value = value.trim();
+        match key {
+            "frame" => self.frame = value.parse().unwrap_or(self.frame),
+            // both are in microseconds, despite the name
+            "out_time_us" | "out_time_ms" => {
+                if let Ok(us) = value.parse::<u64>() {
+                    self.out_time = us as f64 / 1_000_000.0;
+                }
+            }
+            "speed" => self.speed = value.trim_end_matches('x').parse().ok(),
+            "progress" => return true,
+            _ => {}
+        }
+        false
+    }
+
+    fn status(&self, length: f64, audio: &AudioMode) -> StatusUpdate {
+        let percent = if length > 0.0 {
+            (self.out_time / length * 100.0).clamp(0.0, 100.0)
+        } else {
+            0.0
+        };
+        let eta_secs = self
+            .speed
+            .filter(|speed| *speed > 0.0)
+            .map(|speed| (length - self.out_time).max(0.0) / speed);
+        StatusUpdate::Processing {
+            percent,
+            speed: self.speed,
+            eta_secs,
+            audio: audio.clone(),
+        }
+    }
+}
+
+fn visualizer_filter(kind: VisualizerKind, size: Size, fps: u32) -> Filter {
+    match kind {
+        VisualizerKind::None => unreachable!("only called for animated renders"),
+        VisualizerKind::Waves => Filter::new("showwaves")
+            .opt("size", size)
+            .opt("mode", "cline")
+            .opt("rate", fps)
+            .opt("colors", "white"),
+        VisualizerKind::Spectrum => Filter::new("showspectrum")
+            .opt("size", size)
+            .opt("slide", "scroll")
+            .opt("mode", "combined")
+            .opt("color", "intensity"),
+        VisualizerKind::Vectorscope => Filter::new("avectorscope")
+            .opt("size", size)
+            .opt("rate", fps)
+            .opt("draw", "line"),
+    }
+}
+
+/// joins the album tracks into one lossless file at `audio_path`
+async fn concat_tracks(job: &JobInfo) -> Result<(), FFmpegProcessError> {
     let mut cmd = Command::new("ffmpeg");
-    cmd.arg("-i")
-        .arg(&*job.image_path)
-        .arg("-i")
-        .arg(&*job.audio_path);
-
-    let (image_width, image_height) = match job.frame.fit {
-        Fit::Resize => {
-            let f = clamp_resize(
-                job.frame.image_size.0,
-                job.frame.image_size.1,
-                job.image_size.0,
-                job.image_size.1,
-            );
-            let scaled_w = job.image_size.0 as f64 * f;
-            let scaled_h = job.image_size.1 as f64 * f;
-            (scaled_w as u32, scaled_h as u32)
+    cmd.arg("-hide_banner").arg("-nostats");
+    let mut graph = Graph::default();
+    for (i, track) in job.tracks.iter().enumerate() {
+        cmd.arg("-i").arg(&*track.audio_path);
+        // concat needs every input in the same format
+        graph.chain(
+            [Pad::Stream(i, "a:0")],
+            [
+                Filter::new("aresample").value(48000),
+                Filter::new("aformat")
+                    .opt("sample_fmts", "s32")
+                    .opt("channel_layouts", "stereo"),
+            ],
+            [Pad::label(format!("a{i}"))],
+        );
+    }
+    graph.chain(
+        (0..job.tracks.len()).map(|i| Pad::label(format!("a{i}"))),
+        [Filter::new("concat")
+            .opt("n", job.tracks.len())
+            .opt("v", 0)
+            .opt("a", 1)],
+        [Pad::label("audio")],
+    );
+    let filter = graph.to_string();
+
+    let status = cmd
+        .arg("-filter_complex")
+        .arg(filter)
+        .arg("-map")
+        .arg("[audio]")
+        .arg("-c:a")
+        .arg("flac")
+        .arg("-f")
+        .arg("matroska")
+        .arg("-y")
+        .arg(&*job.audio_path)
+        .kill_on_drop(true)
+        .status()
+        .await
+        .map_err(FFmpegProcessError::SpawnError)?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(FFmpegProcessError::FfmpegError(status))
+    }
+}
+
+/// ffmetadata with the job's chapters
+fn chapters(job: &JobInfo) -> String {
+    let escape = |s: &str| {
+        let mut out = String::with_capacity(s.len());
+        for c in s.chars() {
+            if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+                out.push('\\');
+            }
+            out.push(c);
         }
-        Fit::Stretch => job.frame.image_size,
+        out
     };
 
-    let (image_x, image_y) = (
-        (job.frame.frame_size.0 - image_width) / 2,
-        (job.frame.frame_size.1 - image_height) / 2,
+    let mut out = String::from(";FFMETADATA1\n");
+    for chapter in &job.chapters {
+        out += &format!(
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            (chapter.start * 1000.0) as u64,
+            (chapter.end * 1000.0) as u64,
+            escape(&chapter.title)
+        );
+    }
+    out
+}
+
+fn subtitles_filter(lyrics: &Lyrics) -> Filter {
+    let style = &lyrics.config;
+    let force_style = format!(
+        "FontName={},FontSize={},PrimaryColour={},OutlineColour={},BorderStyle=1,Outline={},Shadow={},Alignment={},MarginV={}",
+        style.font,
+        style.font_size,
+        style.primary_color,
+        style.outline_color,
+        style.outline,
+        style.shadow,
+        style.alignment,
+        style.margin,
     );
+    let mut filter = Filter::new("subtitles")
+        .opt("filename", lyrics.path.to_string_lossy())
+        .opt("force_style", force_style);
+    if let Some(dir) = style.fonts_dir.as_ref() {
+        filter = filter.opt("fontsdir", dir.to_string_lossy());
+    }
+    filter
+}
 
-    let (mut output_w, mut output_h) = (
-        job.frame.frame_size.0 + job.frame.x,
-        job.frame.frame_size.1 + job.frame.y,
+/// the image on the frame on the background as `[segment{k}]`, held by `hold`
+pub fn frame_segment(
+    graph: &mut Graph,
+    frame: &FrameConfig,
+    input: Pad,
+    k: usize,
+    image_size: Size,
+    hold: Vec<Filter>,
+) {
+    let label = |name: &str| Pad::label(format!("{name}{k}"));
+    let frame_size = Size::from(frame.frame_size);
+    let output_size = frame_size.offset_by(frame.x, frame.y);
+    let image = fit_image(frame, image_size);
+    let (image_x, image_y) = image.centered_on(frame_size);
+    graph.chain(
+        [],
+        [Filter::new("color")
+            .opt("color", &frame.void_color)
+            .opt("size", output_size)],
+        [label("bg")],
+    );
+    let source = match frame.fit {
+        Fit::BlurFill => {
+            let brightness = frame.blur.brightness;
+            graph.chain(
+                [input],
+                [Filter::new("split")],
+                [label("source"), label("blur")],
+            );
+            graph.chain(
+                [label("blur")],
+                [
+                    Filter::new("scale")
+                        .opt("size", frame_size)
+                        .opt("force_original_aspect_ratio", "increase"),
+                    Filter::new("crop")
+                        .value(frame_size.width)
+                        .value(frame_size.height),
+                    Filter::new("gblur").opt("sigma", frame.blur.sigma),
+                    Filter::new("colorchannelmixer")
+                        .opt("rr", brightness)
+                        .opt("gg", brightness)
+                        .opt("bb", brightness),
+                    Filter::new("setsar").value(1),
+                ],
+                [label("frame_bg")],
+            );
+            label("source")
+        }
+        _ => {
+            graph.chain(
+                [],
+                [Filter::new("color")
+                    .opt("color", &frame.frame_color)
+                    .opt("size", frame_size)],
+                [label("frame_bg")],
+            );
+            input
+        }
+    };
+    let scale = match frame.fit {
+        Fit::Cover => vec![
+            Filter::new("scale")
+                .opt("size", image)
+                .opt("force_original_aspect_ratio", "increase")
+                .opt("flags", "lanczos"),
+            Filter::new("crop")
+                .value(image.width)
+                .value(image.height)
+                .value(format!("(iw-ow)*{}", frame.focus.0))
+                .value(format!("(ih-oh)*{}", frame.focus.1)),
+        ],
+        _ => vec![Filter::new("scale")
+            .opt("size", image)
+            .opt("flags", "lanczos")],
+    };
+    graph.chain([source], scale, [label("image")]);
+    graph.chain(
+        [label("frame_bg"), label("image")],
+        [Filter::new("overlay").value(image_x).value(image_y)],
+        [label("frame")],
+    );
+    graph.chain(
+        [label("bg"), label("frame")],
+        std::iter::once(Filter::new("overlay").value(frame.x).value(frame.y)).chain(hold),
+        [label("segment")],
     );
+}
+
+/// `layer.text` is already filled in by the upload
+fn drawtext_filter(layer: &TextLayer) -> Filter {
+    let x = match layer.align {
+        TextAlign::Left => layer.x.to_string(),
+        TextAlign::Center => format!("{}-text_w/2", layer.x),
+        TextAlign::Right => format!("{}-text_w", layer.x),
+    };
+    let mut filter = Filter::new("drawtext");
+    if let Some(font) = layer.font.as_ref() {
+        filter = filter.opt("fontfile", font.to_string_lossy());
+    }
+    // expansion would treat `%{...}` in titles as functions
+    filter = filter
+        .opt("expansion", "none")
+        .opt("text", &layer.text)
+        .opt("fontsize", layer.size)
+        .opt("fontcolor", &layer.color)
+        .opt("x", x)
+        .opt("y", layer.y);
+    if layer.outline > 0 {
+        filter = filter
+            .opt("borderw", layer.outline)
+            .opt("bordercolor", &layer.outline_color);
+    }
+    if layer.shadow > 0 {
+        filter = filter
+            .opt("shadowx", layer.shadow)
+            .opt("shadowy", layer.shadow)
+            .opt("shadowcolor", &layer.shadow_color);
+    }
+    filter
+}
+
+fn fit_image(frame: &FrameConfig, image_size: Size) -> Size {
+    match frame.fit {
+        Fit::Resize | Fit::BlurFill => image_size.fit_within(frame.image_size.into()),
+        Fit::Stretch | Fit::Cover => frame.image_size.into(),
+    }
+}
+
+/// first `loudnorm` pass, only analyzes the audio
+async fn measure_loudness(
+    audio_path: &Path,
+    target: &LoudnessConfig,
+) -> Result<LoudnessMeasurement, FFmpegProcessError> {
+    #[derive(Deserialize)]
+    struct Loudnorm {
+        input_i: String,
+        input_tp: String,
+        input_lra: String,
+        input_thresh: String,
+        target_offset: String,
+    }
 
-    let mut filter = String::new();
+    let out = Command::new("ffmpeg")
+        .arg("-hide_banner")
+        .arg("-nostats")
+        .arg("-i")
+        .arg(audio_path)
+        .arg("-map")
+        .arg("0:a:0")
+        .arg("-af")
+        .arg(format!(
+            "loudnorm=I={}:TP={}:LRA={}:print_format=json",
+            target.integrated, target.true_peak, target.lra
+        ))
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .kill_on_drop(true)
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(FFmpegProcessError::SpawnError)?;
+    if !out.status.success() {
+        return Err(FFmpegProcessError::FfmpegError(out.status));
+    }
 
-    if job.frame.enable {
-        let mut has_watermark = false;
+    // the json block is the last thing loudnorm prints
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let json = stderr
+        .rfind('{')
+        .and_then(|start| Some(&stderr[start..=start + stderr[start..].rfind('}')?]))
+        .ok_or(FFmpegProcessError::LoudnessParseError(
+            "no json in ffmpeg output".into(),
+        ))?;
+    let parsed: Loudnorm = serde_json::from_str(json)
+        .map_err(|e| FFmpegProcessError::LoudnessParseError(e.to_string().into()))?;
+
+    let parse = |v: &str| {
+        v.parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| {
+                FFmpegProcessError::LoudnessParseError(format!("invalid value {v:?}").into())
+            })
+    };
+    let measured = LoudnessMeasurement {
+        integrated: parse(&parsed.input_i)?,
+        true_peak: parse(&parsed.input_tp)?,
+        lra: parse(&parsed.input_lra)?,
+        threshold: parse(&parsed.input_thresh)?,
+        offset: parse(&parsed.target_offset)?,
+    };
+    info!(?measured, "measured loudness");
+    Ok(measured)
+}
+
+async fn run_ffmpeg(
+    job: Arc<JobInfo>,
+    output_path: Arc<Path>,
+    loudness: Option<LoudnessMeasurement>,
+    tx: &StatusHandle,
+) -> Result<File, FFmpegProcessError> {
+    let visualizer = &job.frame.visualizer;
+    let animated = visualizer.kind != VisualizerKind::None;
+
+    // album tracks with their own cover switch the image at the chapter marks
+    let segments: Vec<(&Path, (u32, u32), f64)> =
+        if job.tracks.iter().any(|track| track.cover.is_some()) {
+            job.tracks
+                .iter()
+                .map(|track| match &track.cover {
+                    Some(cover) => (&*cover.path, cover.size, track.length),
+                    None => (&*job.image_path, job.image_size, track.length),
+                })
+                .collect()
+        } else {
+            vec![(&*job.image_path, job.image_size, job.audio_length)]
+        };
+    let burn = job
+        .lyrics
+        .as_ref()
+        .filter(|lyrics| lyrics.mode == LyricsMode::Burn);
+    let moving = animated || burn.is_some() || segments.len() > 1;
+    let rate = match burn {
+        _ if animated => visualizer.fps,
+        Some(lyrics) => lyrics.config.fps,
+        None => 1,
+    };
+
+    let mut cmd = Command::new("ffmpeg");
+    let image_input = |cmd: &mut Command, path: &Path| {
+        if moving {
+            cmd.arg("-loop")
+                .arg("1")
+                .arg("-framerate")
+                .arg(rate.to_string());
+        }
+        cmd.arg("-i").arg(path);
+    };
+    // the audio stays input 1, the other images come after it
+    image_input(&mut cmd, segments[0].0);
+    cmd.arg("-i").arg(&*job.audio_path);
+    for (path, _, _) in &segments[1..] {
+        image_input(&mut cmd, path);
+    }
+    let mut next_input = segments.len() + 1;
+
+    let frame_size = Size::from(job.frame.frame_size);
+    let output_size = frame_size.offset_by(job.frame.x, job.frame.y);
+    // without the frame every segment is scaled to the first image
+    let video_size = if job.frame.enable {
+        output_size
+    } else {
+        Size::from(segments[0].1)
+    };
+
+    let watermark_style = &job.frame.watermark_style;
+    let mut watermark_input = None;
+    if job.frame.enable || watermark_style.without_frame {
         if let Some(path) = job.frame.watermark.as_ref() {
             cmd.arg("-i").arg(path);
-            let path = path.clone();
-            let (w, h) = spawn_blocking(move || image_dimensions(path)).await??;
-            output_w = output_w.max(w);
-            output_h = output_h.max(h);
-            has_watermark = true;
-        }
-        filter += &format!(
-	        "color=color={}:size={output_w}x{output_h}[bg];[0]scale={image_width}x{image_height}:flags=lanczos[image];color=color={}:size={}x{}[frame_bg];[frame_bg][image]overlay={image_x}:{image_y}[frame];[bg][frame]overlay={}:{},loop=-1,setpts={}/TB[full];{}",
-	        &job.frame.void_color,
-	        &job.frame.frame_color,
-	        job.frame.frame_size.0,
-	        job.frame.frame_size.1,
-	        job.frame.x,
-	        job.frame.y,
-	        job.audio_length,
-	        if has_watermark {
-	            "[full][2]overlay=0:0[output]"
-	        } else {
-	            "[full]null[output]"
-	        }
-	    );
+            watermark_input = Some(next_input);
+            next_input += 1;
+        }
+    }
+
+    let chapters_input = if job.chapters.is_empty() {
+        None
     } else {
-        filter += &format!("[0]loop=-1,setpts={}/TB[output]", job.audio_length);
+        let path = job.output_path.with_extension("ffmeta");
+        tokio::fs::write(&path, chapters(&job)).await?;
+        cmd.arg("-f").arg("ffmetadata").arg("-i").arg(path);
+        Some(next_input)
+    };
+
+    let mut graph = Graph::default();
+    let label = |name: &str, k: usize| Pad::label(format!("{name}{k}"));
+
+    for (k, &(_, image_size, length)) in segments.iter().enumerate() {
+        let input = Pad::Input(if k == 0 { 0 } else { k + 1 });
+        // the image is held for the whole song unless something moves on top of it
+        let hold = if moving {
+            vec![
+                Filter::new("fps").value(rate),
+                Filter::new("trim").opt("duration", length),
+                Filter::new("setpts").value("PTS-STARTPTS"),
+            ]
+        } else {
+            vec![
+                Filter::new("loop").value(-1),
+                Filter::new("setpts").value(format!("{length}/TB")),
+            ]
+        };
+        if job.frame.enable {
+            frame_segment(&mut graph, &job.frame, input, k, image_size.into(), hold);
+        } else if segments.len() > 1 {
+            // concat needs every segment in the same size
+            let scale = [
+                Filter::new("scale")
+                    .opt("size", Size::from(segments[0].1))
+                    .opt("flags", "lanczos"),
+                Filter::new("setsar").value(1),
+            ];
+            graph.chain(
+                [input],
+                scale.into_iter().chain(hold),
+                [label("segment", k)],
+            );
+        } else {
+            graph.chain([input], hold, [label("segment", k)]);
+        }
     }
 
+    let video = Pad::label(if burn.is_some() { "video" } else { "output" });
+    let still = if animated {
+        Pad::label("still")
+    } else {
+        video.clone()
+    };
+    if segments.len() > 1 {
+        graph.chain(
+            (0..segments.len()).map(|k| label("segment", k)),
+            [Filter::new("concat")
+                .opt("n", segments.len())
+                .opt("v", 1)
+                .opt("a", 0)],
+            [Pad::label("full")],
+        );
+    } else {
+        graph.chain(
+            [label("segment", 0)],
+            [Filter::new("null")],
+            [Pad::label("full")],
+        );
+    }
+    let mut inputs = vec![Pad::label("full")];
+    let mut filters = Vec::new();
+    if let Some(input) = watermark_input {
+        let mut watermark = Vec::new();
+        if let Some(scale) = watermark_style.scale {
+            let width = ((video_size.width as f64 * scale) as u32).max(1);
+            watermark.push(Filter::new("scale").opt("w", width).opt("h", -1));
+        }
+        watermark.push(Filter::new("format").value("rgba"));
+        watermark.push(Filter::new("colorchannelmixer").opt("aa", watermark_style.opacity));
+        graph.chain([Pad::Input(input)], watermark, [Pad::label("watermark")]);
+
+        let margin = watermark_style.margin;
+        let (x, y) = match watermark_style.anchor {
+            Anchor::TopLeft => (margin.to_string(), margin.to_string()),
+            Anchor::TopRight => (format!("W-w-{margin}"), margin.to_string()),
+            Anchor::BottomLeft => (margin.to_string(), format!("H-h-{margin}")),
+            Anchor::BottomRight => (format!("W-w-{margin}"), format!("H-h-{margin}")),
+            Anchor::Center => ("(W-w)/2".to_owned(), "(H-h)/2".to_owned()),
+        };
+        inputs.push(Pad::label("watermark"));
+        filters.push(Filter::new("overlay").opt("x", x).opt("y", y));
+    }
+    filters.extend(
+        job.frame
+            .text
+            .iter()
+            .filter(|layer| job.frame.enable || layer.without_frame)
+            .map(drawtext_filter),
+    );
+    if filters.is_empty() {
+        filters.push(Filter::new("null"));
+    }
+    graph.chain(inputs, filters, [still]);
+
+    if animated {
+        let size = Size::from(visualizer.size);
+        let fps = visualizer.fps;
+        // the visualization is turned into a mask so every kind can be tinted the same way
+        graph.chain(
+            [Pad::Stream(1, "a")],
+            [
+                visualizer_filter(visualizer.kind, size, fps),
+                Filter::new("fps").value(fps),
+                Filter::new("format").value("gray"),
+            ],
+            [Pad::label("vis_mask")],
+        );
+        graph.chain(
+            [],
+            [Filter::new("color")
+                .opt("color", &visualizer.color)
+                .opt("size", size)
+                .opt("rate", fps)
+                .opt("duration", job.audio_length)],
+            [Pad::label("vis_color")],
+        );
+        graph.chain(
+            [Pad::label("vis_color"), Pad::label("vis_mask")],
+            [
+                Filter::new("alphamerge"),
+                Filter::new("format").value("rgba"),
+                Filter::new("colorchannelmixer").opt("aa", visualizer.opacity),
+            ],
+            [Pad::label("vis")],
+        );
+        graph.chain(
+            [Pad::label("still")],
+            [Filter::new("fps").value(fps)],
+            [Pad::label("base")],
+        );
+        graph.chain(
+            [Pad::label("base"), Pad::label("vis")],
+            [Filter::new("overlay")
+                .value(visualizer.x)
+                .value(visualizer.y)
+                .opt("shortest", 1)],
+            [video.clone()],
+        );
+    }
+
+    if let Some(lyrics) = burn {
+        graph.chain([video], [subtitles_filter(lyrics)], [Pad::label("output")]);
+    }
+
+    let filter = graph.to_string();
     debug!("filtergraph: {filter}");
 
+    let encoding = &job.encoding;
     cmd.arg("-hide_banner")
         .arg("-filter_complex")
         .arg(filter)
         .arg("-c:v")
-        .arg("libx264")
-        .arg("-frames:v")
-        .arg("1")
+        .arg(&encoding.codec)
         .arg("-pix_fmt")
-        .arg("yuv444p")
-        .arg("-crf")
-        .arg("0")
-        .arg("-c:a")
-        .arg("copy")
-        .arg("-map")
+        .arg(&encoding.pix_fmt);
+    if moving {
+        cmd.arg("-shortest");
+    } else {
+        cmd.arg("-frames:v").arg("1");
+    }
+    if let Some(crf) = encoding.crf {
+        cmd.arg("-crf").arg(crf.to_string());
+    } else if let Some(bitrate) = encoding.bitrate.as_ref() {
+        cmd.arg("-b:v").arg(bitrate);
+    }
+    if let Some(tune) = encoding.tune.as_ref() {
+        cmd.arg("-tune").arg(tune);
+    }
+    if let Some(preset) = encoding.preset.as_ref() {
+        cmd.arg("-preset").arg(preset);
+    }
+    match &job.audio {
+        AudioMode::Copy { .. } => {
+            cmd.arg("-c:a").arg("copy");
+        }
+        AudioMode::Transcode { to, .. } => {
+            cmd.arg("-c:a").arg(to);
+            if let Some(bitrate) = encoding.audio_bitrate.as_ref() {
+                cmd.arg("-b:a").arg(bitrate);
+            }
+        }
+    }
+    if let (Some(target), Some(measured)) = (job.normalize.as_ref(), loudness) {
+        // loudnorm works at 192kHz, youtube serves 48kHz anyway
+        cmd.arg("-af").arg(format!(
+            "loudnorm=I={}:TP={}:LRA={}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true,aresample=48000",
+            target.integrated,
+            target.true_peak,
+            target.lra,
+            measured.integrated,
+            measured.true_peak,
+            measured.lra,
+            measured.threshold,
+            measured.offset,
+        ));
+    }
+    cmd.arg("-map")
         .arg("[output]:v")
         .arg("-map")
         .arg("1:a")
         .arg("-map_metadata")
-        .arg("1")
-        .arg("-f")
-        .arg("matroska")
-        .arg("-");
-
-    let out = File::create_new(&output_path).await?;
-    let cloned_fd = out.try_clone().await?;
+        .arg("1");
+    if let Some(input) = chapters_input {
+        cmd.arg("-map_chapters").arg(input.to_string());
+    }
+    cmd.arg("-f")
+        .arg(encoding.container.format())
+        .arg("-progress")
+        .arg("pipe:1")
+        .arg("-nostats")
+        .arg("-y")
+        .arg(&*output_path);
+
+    // reserve the path, ffmpeg writes into it
+    File::create_new(&output_path).await?;
     let mut child = cmd
-        .stdout(out.into_std().await)
+        .kill_on_drop(true)
+        .stdout(Stdio::piped())
         .spawn()
         .map_err(FFmpegProcessError::SpawnError)?;
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let mut lines = BufReader::new(stdout).lines();
+    let mut progress = FfmpegProgress::default();
+    while let Some(line) = lines.next_line().await? {
+        if progress.feed(&line) {
+            trace!(?progress, "ffmpeg progress");
+            tx.send(progress.status(job.audio_length, &job.audio));
+        }
+    }
     let status = child.wait().await?;
 
     if status.success() {
         debug!("output: {}", output_path.display());
-        Ok(cloned_fd)
+        Ok(File::open(&output_path).await?)
     } else {
         Err(FFmpegProcessError::FfmpegError(status))
     }
 }
 
+/// minimum time between two upload progress updates
+const UPLOAD_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+/// rates over shorter spans are mostly noise, or a division by zero
+const MIN_RATE_WINDOW: Duration = Duration::from_millis(100);
+
+/// counts bytes handed to the request body and reports them, throttled
+struct UploadProgress {
+    tx: StatusHandle,
+    sent: u64,
+    total: u64,
+    last_sent: u64,
+    last: Instant,
+}
+
+impl UploadProgress {
+    fn new(tx: StatusHandle, sent: u64, total: u64) -> Self {
+        Self {
+            tx,
+            sent,
+            total,
+            last_sent: sent,
+            last: Instant::now(),
+        }
+    }
+
+    fn add(&mut self, len: usize) {
+        self.sent += len as u64;
+        let elapsed = self.last.elapsed();
+        if elapsed < UPLOAD_PROGRESS_INTERVAL && self.sent < self.total {
+            return;
+        }
+
+        let rate = (elapsed >= MIN_RATE_WINDOW)
+            .then(|| (self.sent - self.last_sent) as f64 / elapsed.as_secs_f64());
+        self.last_sent = self.sent;
+        self.last = Instant::now();
+        self.tx.send(StatusUpdate::Uploading {
+            sent: self.sent,
+            total: self.total,
+            rate,
+        });
+    }
+}
+
+fn retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// exponential backoff with a budget for the whole upload
+struct Backoff {
+    delay: Duration,
+    initial: Duration,
+    spent: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    const MAX_DELAY: Duration = Duration::from_secs(64);
+
+    fn new(config: &YoutubeConfig) -> Self {
+        let initial = Duration::from_millis(config.retry_delay);
+        Self {
+            delay: initial,
+            initial,
+            spent: Duration::ZERO,
+            max: Duration::from_millis(config.retry_time),
+        }
+    }
+
+    /// sleeps before the next attempt, `attempt` being when the failed one started
+    async fn wait(&mut self, attempt: Instant) -> Result<(), YTUploadError> {
+        self.spent += attempt.elapsed();
+        let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..1000));
+        let delay = self.delay + jitter;
+        if self.spent + delay > self.max {
+            return Err(YTUploadError::RetriesExhausted(self.spent));
+        }
+        warn!("retrying upload in {delay:?}");
+        sleep(delay).await;
+        self.spent += delay;
+        self.delay = (self.delay * 2).min(Self::MAX_DELAY);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.delay = self.initial;
+    }
+}
+
+enum UploadStep {
+    /// the server has every byte before this offset
+    Incomplete(u64),
+    Complete(UploadResponseSuccess),
+    Retry,
+}
+
+async fn upload_step(
+    res: Result<reqwest::Response, reqwest::Error>,
+) -> Result<UploadStep, YTUploadError> {
+    let res = match res {
+        Ok(res) => res,
+        Err(err) => {
+            warn!("upload request failed: {err}");
+            return Ok(UploadStep::Retry);
+        }
+    };
+
+    let status = res.status();
+    if status == StatusCode::PERMANENT_REDIRECT {
+        // "Resume Incomplete", the range is inclusive and missing if nothing was stored
+        let offset = res
+            .headers()
+            .get(header::RANGE)
+            .and_then(|range| range.to_str().ok())
+            .and_then(|range| range.strip_prefix("bytes=0-"))
+            .and_then(|end| end.parse::<u64>().ok())
+            .map_or(0, |end| end + 1);
+        return Ok(UploadStep::Incomplete(offset));
+    } else if retryable(status) {
+        warn!(?status, "upload got a retryable response");
+        return Ok(UploadStep::Retry);
+    }
+
+    let parsed: UploadResponseResult = match res.json().await {
+        Ok(v) => v,
+        // the body got cut off, ask the server where we are
+        Err(err) if status.is_success() || err.is_timeout() => {
+            warn!("failed to read upload response: {err}");
+            return Ok(UploadStep::Retry);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    debug!("upload response {parsed:?}");
+
+    match parsed {
+        UploadResponseResult::Ok(v) => Ok(UploadStep::Complete(v)),
+        UploadResponseResult::Err(err) => Err(err.into()),
+    }
+}
+
 async fn upload(
     file: File,
     size: u64,
     meta: &Metadata,
     auth: &TokenClaim,
+    container: Container,
+    config: &YoutubeConfig,
+    tx: &StatusHandle,
 ) -> Result<String, YTUploadError> {
     let client = reqwest::ClientBuilder::new()
         .redirect(reqwest::redirect::Policy::none())
         .build()?;
+    // a stalled connection never errors on its own
+    let timeout = Duration::from_millis(config.timeout);
+    let chunk_timeout =
+        |len: u64| timeout + Duration::from_secs_f64(len as f64 / config.min_upload_rate as f64);
 
     let bearer = format!("Bearer {}", auth.access_token);
 
     let privacy: &str = meta.privacy.into();
 
-    let json = json!({
+    let mut json = json!({
         "snippet": {
             "title": meta.title.as_deref(),
             "description": &meta.description,
@@ -435,22 +1672,72 @@ async fn upload(
             "selfDeclaredMadeForKids": meta.made_for_kids,
         }
     });
+    let format = |at: OffsetDateTime| {
+        at.format(&Rfc3339)
+            .map_err(|e| YTUploadError::Other(e.to_string().into()))
+    };
+    if let Some(publish_at) = meta.publish_at {
+        json["status"]["publishAt"] = format(publish_at)?.into();
+    }
+    if let Some(language) = meta.default_language.as_ref() {
+        json["snippet"]["defaultLanguage"] = language.as_str().into();
+    }
+    if let Some(language) = meta.default_audio_language.as_ref() {
+        json["snippet"]["defaultAudioLanguage"] = language.as_str().into();
+    }
+    if let Some(license) = meta.license {
+        json["status"]["license"] = serde_json::to_value(license)?;
+    }
+    if let Some(embeddable) = meta.embeddable {
+        json["status"]["embeddable"] = embeddable.into();
+    }
+    if let Some(viewable) = meta.public_stats_viewable {
+        json["status"]["publicStatsViewable"] = viewable.into();
+    }
+    let mut part = String::from("snippet,id,status");
+    if let Some(date) = meta.recording_date {
+        json["recordingDetails"] = json!({ "recordingDate": format(date)? });
+        part += ",recordingDetails";
+    }
+    if !meta.localizations.is_empty() {
+        json["localizations"] = serde_json::to_value(&meta.localizations)?;
+        part += ",localizations";
+    }
 
     debug!("uploading with metadata: {meta:?}, body: {json}");
 
-    let url = format!(
-        "https://www.googleapis.com/upload/youtube/v3/videos?uploadType=resumable&part=snippet,id,status&notifySubscribers={}",
-        if meta.notify_subs { "True" } else { "False" } // thanks google
-    );
-    let res = client
-        .post(url)
-        .header(header::AUTHORIZATION, &bearer)
-        .header("X-Upload-Content-Type", "video/x-matroska")
-        .header("X-Upload-Content-Length", size)
-        .header("Slug", &meta.filename)
-        .json(&json)
-        .send()
-        .await?;
+    let mut url = config
+        .api_url
+        .join("upload/youtube/v3/videos")
+        .map_err(|e| YTUploadError::Other(e.to_string().into()))?;
+    url.query_pairs_mut()
+        .append_pair("uploadType", "resumable")
+        .append_pair("part", &part)
+        .append_pair(
+            "notifySubscribers",
+            if meta.notify_subs { "True" } else { "False" }, // thanks google
+        );
+
+    let mut backoff = Backoff::new(config);
+    let res = loop {
+        let attempt = Instant::now();
+        match client
+            .post(url.clone())
+            .header(header::AUTHORIZATION, &bearer)
+            .header("X-Upload-Content-Type", container.mime())
+            .header("X-Upload-Content-Length", size)
+            .header("Slug", &meta.filename)
+            .json(&json)
+            .timeout(timeout)
+            .send()
+            .await
+        {
+            Ok(res) if !retryable(res.status()) => break res,
+            Ok(res) => warn!(status = ?res.status(), "upload session got a retryable response"),
+            Err(err) => warn!("failed to start upload session: {err}"),
+        }
+        backoff.wait(attempt).await?;
+    };
 
     let status = res.status();
     if !status.is_success() {
@@ -465,41 +1752,430 @@ async fn upload(
         .get(header::LOCATION)
         .ok_or(YTUploadError::Other("expected location heaader".into()))?
         .to_str()
-        .map_err(|e| YTUploadError::Other(e.to_string().into()))?;
+        .map_err(|e| YTUploadError::Other(e.to_string().into()))?
+        .to_owned();
 
     debug!("resumable upload uri: {location}");
 
+    let mut offset = 0;
+    // after a failure the server may have kept any part of the last chunk
+    let mut lost_track = false;
+    backoff.reset();
+    let data = loop {
+        let attempt = Instant::now();
+        let res = if lost_track || offset >= size {
+            client
+                .put(&location)
+                .header(header::AUTHORIZATION, &bearer)
+                .header(header::CONTENT_RANGE, format!("bytes */{size}"))
+                .header(header::CONTENT_LENGTH, 0)
+                .timeout(timeout)
+                .send()
+                .await
+        } else {
+            let end = (offset + config.chunk_size).min(size);
+            let mut chunk = file.try_clone().await?;
+            chunk.seek(SeekFrom::Start(offset)).await?;
+            let mut progress = UploadProgress::new(tx.clone(), offset, size);
+            client
+                .put(&location)
+                .header(header::AUTHORIZATION, &bearer)
+                .header(header::CONTENT_TYPE, container.mime())
+                .header(header::CONTENT_LENGTH, end - offset)
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {offset}-{}/{size}", end - 1),
+                )
+                .body(reqwest::Body::wrap_stream(
+                    ReaderStream::new(chunk.take(end - offset))
+                        .inspect_ok(move |chunk| progress.add(chunk.len())),
+                ))
+                .timeout(chunk_timeout(end - offset))
+                .send()
+                .await
+        };
+
+        match upload_step(res).await? {
+            UploadStep::Complete(data) => break data,
+            UploadStep::Incomplete(confirmed) => {
+                debug!("server confirmed {confirmed} of {size} bytes");
+                if lost_track {
+                    info!("resuming upload at byte {confirmed}");
+                }
+                if confirmed > offset {
+                    backoff.reset();
+                } else if !lost_track {
+                    // nothing was gained, don't hammer a server that keeps saying so
+                    backoff.wait(attempt).await?;
+                }
+                offset = confirmed;
+                lost_track = false;
+            }
+            UploadStep::Retry => {
+                lost_track = true;
+                backoff.wait(attempt).await?;
+            }
+        }
+    };
+
+    debug!("uploaded: {data:?}");
+
+    Ok(data.id)
+}
+
+/// adds the lyrics as a caption track to an uploaded video
+async fn upload_captions(
+    video_id: &str,
+    lyrics: &Lyrics,
+    auth: &TokenClaim,
+    config: &YoutubeConfig,
+) -> Result<(), YTUploadError> {
+    let mut url = config
+        .api_url
+        .join("upload/youtube/v3/captions")
+        .map_err(|e| YTUploadError::Other(e.to_string().into()))?;
+    url.query_pairs_mut()
+        .append_pair("uploadType", "multipart")
+        .append_pair("part", "snippet");
+
+    let json = json!({
+        "snippet": {
+            "videoId": video_id,
+            "language": &lyrics.config.language,
+            "name": &lyrics.config.caption_name,
+            "isDraft": false,
+        }
+    });
+    let srt = tokio::fs::read(&lyrics.path).await?;
+
+    // the captions endpoint wants multipart/related, which reqwest can't build
+    let boundary = format!("caption_{}", Ulid::new());
+    let mut body = format!(
+        "--{boundary}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{json}\r\n--{boundary}\r\nContent-Type: application/octet-stream\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(&srt);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    let res = reqwest::Client::new()
+        .post(url)
+        .bearer_auth(&auth.access_token)
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/related; boundary={boundary}"),
+        )
+        .body(body)
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        let err: GoogleErrorResponse = res.json().await?;
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+const THUMBNAIL_SIZE: (u32, u32) = (1280, 720);
+/// youtube rejects larger thumbnails
+const THUMBNAIL_MAX: usize = 2_000_000;
+
+/// the image letterboxed into a 16:9 jpeg
+fn make_thumbnail(path: &Path) -> Result<Vec<u8>, ThumbnailError> {
+    let (w, h) = THUMBNAIL_SIZE;
+    let image = ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()?
+        .resize(w, h, FilterType::Lanczos3)
+        .into_rgb8();
+    let mut canvas = RgbImage::new(w, h);
+    overlay(
+        &mut canvas,
+        &image,
+        ((w - image.width()) / 2).into(),
+        ((h - image.height()) / 2).into(),
+    );
+
+    for quality in [90, 80, 70, 60, 50] {
+        let mut jpeg = Vec::new();
+        canvas.write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, quality))?;
+        if jpeg.len() < THUMBNAIL_MAX {
+            return Ok(jpeg);
+        }
+    }
+    Err(ThumbnailError::TooLarge)
+}
+
+async fn set_thumbnail(
+    video_id: &str,
+    info: &JobInfo,
+    auth: &TokenClaim,
+    config: &YoutubeConfig,
+) -> Result<(), ThumbnailError> {
+    let path = info.thumbnail.as_ref().unwrap_or(&info.image_path).clone();
+    let jpeg = spawn_blocking(move || make_thumbnail(&path)).await??;
+
+    let mut url = config
+        .api_url
+        .join("upload/youtube/v3/thumbnails/set")
+        .map_err(|e| YTUploadError::Other(e.to_string().into()))?;
+    url.query_pairs_mut()
+        .append_pair("videoId", video_id)
+        .append_pair("uploadType", "media");
+
+    let res = reqwest::Client::new()
+        .post(url)
+        .bearer_auth(&auth.access_token)
+        .header(header::CONTENT_TYPE, "image/jpeg")
+        .body(jpeg)
+        .send()
+        .await
+        .map_err(YTUploadError::from)?;
+    if !res.status().is_success() {
+        let err: GoogleErrorResponse = res.json().await.map_err(YTUploadError::from)?;
+        return Err(YTUploadError::from(err).into());
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistPage {
+    #[serde(default)]
+    items: Vec<Playlist>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Playlist {
+    id: String,
+    snippet: PlaylistSnippet,
+}
+
+#[derive(Deserialize)]
+struct PlaylistSnippet {
+    title: String,
+}
+
+async fn google_json<T: serde::de::DeserializeOwned>(
+    res: reqwest::Response,
+) -> Result<T, YTUploadError> {
+    if res.status().is_success() {
+        Ok(res.json().await?)
+    } else {
+        let err: GoogleErrorResponse = res.json().await?;
+        Err(err.into())
+    }
+}
+
+/// every playlist of the user's channel
+async fn list_playlists(
+    client: &reqwest::Client,
+    auth: &TokenClaim,
+    config: &YoutubeConfig,
+) -> Result<Vec<Playlist>, YTUploadError> {
+    let url = config
+        .api_url
+        .join("youtube/v3/playlists")
+        .map_err(|e| YTUploadError::Other(e.to_string().into()))?;
+    let mut playlists = Vec::new();
+    let mut page_token = None;
+    loop {
+        let mut url = url.clone();
+        url.query_pairs_mut()
+            .append_pair("part", "snippet")
+            .append_pair("mine", "true")
+            .append_pair("maxResults", "50");
+        if let Some(token) = page_token.as_deref() {
+            url.query_pairs_mut().append_pair("pageToken", token);
+        }
+        let res = client
+            .get(url)
+            .bearer_auth(&auth.access_token)
+            .send()
+            .await?;
+        let page: PlaylistPage = google_json(res).await?;
+        playlists.extend(page.items);
+        match page.next_page_token {
+            Some(token) => page_token = Some(token),
+            None => break Ok(playlists),
+        }
+    }
+}
+
+async fn create_playlist(
+    client: &reqwest::Client,
+    title: &str,
+    privacy: Privacy,
+    auth: &TokenClaim,
+    config: &YoutubeConfig,
+) -> Result<Playlist, YTUploadError> {
+    let mut url = config
+        .api_url
+        .join("youtube/v3/playlists")
+        .map_err(|e| YTUploadError::Other(e.to_string().into()))?;
+    url.query_pairs_mut().append_pair("part", "snippet,status");
+    let privacy: &str = privacy.into();
     let res = client
-        .post(location)
-        .header(header::AUTHORIZATION, &bearer)
-        .header(header::CONTENT_TYPE, "video/x-matroska")
-        .header(header::CONTENT_LENGTH, size)
-        .body(reqwest::Body::wrap_stream(ReaderStream::new(file)))
+        .post(url)
+        .bearer_auth(&auth.access_token)
+        .json(&json!({
+            "snippet": { "title": title },
+            "status": { "privacyStatus": privacy },
+        }))
         .send()
         .await?;
+    google_json(res).await
+}
 
-    let parsed: UploadResponseResult = res.json().await?; // we can be opaque over parsing errors at this point
-    debug!("upload response {parsed:?}");
+/// adds the video to the requested playlists, returns a warning for each failure
+async fn add_to_playlists(
+    video_id: &str,
+    info: &JobInfo,
+    auth: &TokenClaim,
+    config: &YoutubeConfig,
+) -> Vec<String> {
+    let client = reqwest::Client::new();
+    let mut warnings = Vec::new();
 
-    let data = match parsed {
-        UploadResponseResult::Ok(v) => v,
-        UploadResponseResult::Err(err) => return Err(err.into()),
+    let mut playlists = match list_playlists(&client, auth, config).await {
+        Ok(playlists) => playlists,
+        Err(err) => {
+            warn!("failed to list playlists: {err}");
+            warnings.push(format!("failed to list playlists: {err}"));
+            return warnings;
+        }
     };
 
-    debug!("uploaded: {data:?}");
+    let mut url = match config.api_url.join("youtube/v3/playlistItems") {
+        Ok(url) => url,
+        Err(err) => return vec![format!("invalid api url: {err}")],
+    };
+    url.query_pairs_mut().append_pair("part", "snippet");
+
+    let matches =
+        |playlist: &Playlist, name: &str| playlist.id == name || playlist.snippet.title == name;
+    for target in &info.playlists {
+        // `Vol#2` only means `Vol` at position 2 when there's no playlist called `Vol#2`
+        let (name, position) = match target.positioned() {
+            Some((name, position)) if !playlists.iter().any(|p| matches(p, &target.name)) => {
+                (name, Some(position))
+            }
+            _ => (target.name.as_str(), None),
+        };
+        let found = playlists
+            .iter()
+            .find(|p| matches(p, name))
+            .map(|p| p.id.clone());
+        let playlist_id = match found {
+            Some(id) => id,
+            None if config.create_playlists => {
+                match create_playlist(&client, name, info.meta.privacy, auth, config).await {
+                    Ok(playlist) => {
+                        info!(id = %playlist.id, "created playlist {name:?}");
+                        let id = playlist.id.clone();
+                        playlists.push(playlist);
+                        id
+                    }
+                    Err(err) => {
+                        warn!("failed to create playlist {name:?}: {err}");
+                        warnings.push(format!("failed to create playlist {name:?}: {err}"));
+                        continue;
+                    }
+                }
+            }
+            None => {
+                warnings.push(format!("playlist {name:?} not found"));
+                continue;
+            }
+        };
 
-    Ok(data.id)
+        let mut snippet = json!({
+            "playlistId": &playlist_id,
+            "resourceId": { "kind": "youtube#video", "videoId": video_id },
+        });
+        if let Some(position) = position {
+            snippet["position"] = position.into();
+        }
+        let res = client
+            .post(url.clone())
+            .bearer_auth(&auth.access_token)
+            .json(&json!({ "snippet": snippet }))
+            .send()
+            .await;
+        let res = match res {
+            Ok(res) => google_json::<serde_json::Value>(res).await,
+            Err(err) => Err(err.into()),
+        };
+        match res {
+            Ok(_) => info!(%playlist_id, "added to playlist"),
+            Err(err) => {
+                warn!(%playlist_id, "failed to add to playlist: {err}");
+                warnings.push(format!("failed to add to playlist {name:?}: {err}"));
+            }
+        }
+    }
+    warnings
+}
+
+#[derive(Clone)]
+struct Slots {
+    render: Arc<Semaphore>,
+    upload: Arc<Semaphore>,
 }
 
-async fn process_job(info: Arc<JobInfo>, tx: &StatusSender) -> Result<String, VideoProcessError> {
-    tx.send(StatusUpdate::Processing)?;
+async fn process_job(
+    info: Arc<JobInfo>,
+    tx: &StatusHandle,
+    slots: &Slots,
+    config: &'static Config,
+) -> Result<JobOutput, VideoProcessError> {
+    let render_permit = slots
+        .render
+        .acquire()
+        .await
+        .expect("semaphore is never closed");
+    tx.send(StatusUpdate::Processing {
+        percent: 0.0,
+        speed: None,
+        eta_secs: None,
+        audio: info.audio.clone(),
+    });
     info!("processing");
 
+    let burn = info
+        .lyrics
+        .as_ref()
+        .is_some_and(|lyrics| lyrics.mode == LyricsMode::Burn);
+    let max = Duration::from_millis(
+        if info.frame.visualizer.kind == VisualizerKind::None && !burn {
+            info.limits.processing.time
+        } else {
+            info.limits.processing.visualizer_time
+        },
+    );
+
+    // restored jobs and long queue waits can outlive the token from the upload,
+    // a revoked one shouldn't cost a render
+    let client = reqwest::Client::new();
+    let mut auth = info.auth.clone();
+    if refresh_token(&mut auth, max + TOKEN_MARGIN, &config.auth, &client).await? {
+        info!("refreshed access token");
+    }
+
     let ffmpeg_info = info.clone();
     let output_path = info.output_path.clone();
     let start = Instant::now();
-    let max = Duration::from_millis(info.limits.processing.time);
-    let mut file = match timeout(max, run_ffmpeg(ffmpeg_info, output_path)).await {
+    let render = async {
+        if !info.tracks.is_empty() {
+            concat_tracks(&info).await?;
+        }
+        let loudness = match info.normalize.as_ref() {
+            Some(target) => Some(measure_loudness(&info.audio_path, target).await?),
+            None => None,
+        };
+        let file = run_ffmpeg(ffmpeg_info, output_path, loudness, tx).await?;
+        Ok::<_, FFmpegProcessError>((file, loudness))
+    };
+    let (mut file, loudness) = match timeout(max, render).await {
         Ok(value) => value?,
         Err(_) => {
             let elapsed = start.elapsed();
@@ -511,6 +2187,7 @@ async fn process_job(info: Arc<JobInfo>, tx: &StatusSender) -> Result<String, Vi
             .into());
         }
     };
+    drop(render_permit);
     let size = file.seek(SeekFrom::End(0)).await?;
     file.seek(SeekFrom::Start(0)).await?;
     let elapsed = start.elapsed();
@@ -519,82 +2196,550 @@ async fn process_job(info: Arc<JobInfo>, tx: &StatusSender) -> Result<String, Vi
         humansize::format_size(size, humansize::DECIMAL)
     );
 
-    tx.send(StatusUpdate::Uploading)?;
+    let _upload_permit = slots
+        .upload
+        .acquire()
+        .await
+        .expect("semaphore is never closed");
+    if refresh_token(&mut auth, TOKEN_MARGIN, &config.auth, &client).await? {
+        info!("refreshed access token");
+    }
+    tx.send(StatusUpdate::Uploading {
+        sent: 0,
+        total: size,
+        rate: None,
+    });
     info!("uploading");
 
     let upload_start = Instant::now();
-    let id = upload(file, size, &info.meta, &info.auth).await?;
+    let video_id = upload(
+        file,
+        size,
+        &info.meta,
+        &auth,
+        info.encoding.container,
+        &config.youtube,
+        tx,
+    )
+    .await?;
     let elapsed = upload_start.elapsed();
     debug!(
         "upload done in {elapsed:?}, output {}",
         humansize::format_size(size, humansize::DECIMAL)
     );
 
-    Ok(id)
+    let mut warnings = Vec::new();
+    if let Some(lyrics) = info
+        .lyrics
+        .as_ref()
+        .filter(|lyrics| lyrics.mode == LyricsMode::Caption)
+    {
+        match upload_captions(&video_id, lyrics, &auth, &config.youtube).await {
+            Ok(()) => info!("captions uploaded"),
+            Err(err) => {
+                warn!("failed to upload captions: {err}");
+                warnings.push(format!("failed to upload captions: {err}"));
+            }
+        }
+    }
+
+    if config.youtube.thumbnail || info.thumbnail.is_some() {
+        match set_thumbnail(&video_id, &info, &auth, &config.youtube).await {
+            Ok(()) => info!("thumbnail set"),
+            Err(err) => {
+                warn!("failed to set thumbnail: {err}");
+                warnings.push(format!("failed to set thumbnail: {err}"));
+            }
+        }
+    }
+
+    if !info.playlists.is_empty() {
+        warnings.extend(add_to_playlists(&video_id, &info, &auth, &config.youtube).await);
+    }
+
+    Ok(JobOutput {
+        video_id,
+        loudness,
+        warnings,
+        publish_at: info.meta.publish_at,
+    })
+}
+
+async fn cleanup_job(info: &JobInfo) {
+    let image_removed = tokio::fs::remove_file(&info.image_path).await.is_ok();
+    let audio_removed = tokio::fs::remove_file(&info.audio_path).await.is_ok();
+    let video_removed = tokio::fs::remove_file(&info.output_path).await.is_ok();
+    let mut tracks_removed = 0;
+    for track in &info.tracks {
+        tracks_removed += tokio::fs::remove_file(&track.audio_path).await.is_ok() as usize;
+        if let Some(cover) = &track.cover {
+            let _ = tokio::fs::remove_file(&cover.path).await;
+        }
+    }
+    if !info.chapters.is_empty() {
+        let _ = tokio::fs::remove_file(info.output_path.with_extension("ffmeta")).await;
+    }
+    if let Some(lyrics) = &info.lyrics {
+        let _ = tokio::fs::remove_file(&lyrics.path).await;
+    }
+    if let Some(thumbnail) = &info.thumbnail {
+        let _ = tokio::fs::remove_file(thumbnail).await;
+    }
+    info!(%image_removed, %audio_removed, %video_removed, %tracks_removed, "cleanup");
+}
+
+/// removes a job's files without knowing their paths, they're all named `{kind}_{id}[.ext]`
+async fn remove_temp_files(temp_dir: &Path, id: Ulid) -> std::io::Result<usize> {
+    let id = id.to_string();
+    let mut removed = 0;
+    let mut entries = tokio::fs::read_dir(temp_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let ours = name
+            .to_str()
+            .is_some_and(|name| name.split(['_', '.']).any(|part| part == id));
+        if ours && tokio::fs::remove_file(entry.path()).await.is_ok() {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+async fn expire_job(
+    id: Ulid,
+    after: Duration,
+    job_tracker: Arc<JobTracker>,
+    job_store: JobStore,
+    token: CancellationToken,
+) {
+    select! {
+        _ = sleep(after) => {},
+        // keep the stored status around for the next start
+        _ = token.cancelled() => return
+    };
+    job_tracker.remove_async(&id).await;
+    if let Err(err) = job_store.remove(id) {
+        error!(%id, "failed to remove job from store: {err}");
+    }
+    debug!(%id, "dropped job");
 }
 
 pub async fn ffmpeg_task(
-    mut job_input: JobReceiver,
+    job_queue: Arc<JobQueue>,
     job_tracker: Arc<JobTracker>,
+    job_store: JobStore,
+    restored: RestoredJobs,
+    config: &'static Config,
     token: CancellationToken,
 ) {
+    let limits = config.limits.processing;
     let tt = TaskTracker::new();
+    let workers = Arc::new(Semaphore::new(limits.workers));
+    let slots = Slots {
+        render: Arc::new(Semaphore::new(limits.render)),
+        upload: Arc::new(Semaphore::new(limits.upload)),
+    };
 
-    while let Some(job) = select! {
-        v = job_input.recv() => v,
-        _ = token.cancelled() => return
-    } {
-        let QueuedJobInfo { info, tx } = job;
+    for (id, remaining) in restored.finished {
+        tt.spawn(expire_job(
+            id,
+            remaining,
+            job_tracker.clone(),
+            job_store.clone(),
+            token.child_token(),
+        ));
+    }
+    let mut restored = restored.queued.into_iter();
+
+    loop {
+        // wait for a free worker before taking a job so the queue keeps its size
+        let worker = select! {
+            v = workers.clone().acquire_owned() => v.expect("semaphore is never closed"),
+            _ = token.cancelled() => break
+        };
+        let job = match restored.next() {
+            Some(job) => job,
+            None => select! {
+                v = job_queue.pop() => v,
+                _ = token.cancelled() => break
+            },
+        };
+
+        let QueuedJobInfo {
+            info,
+            status,
+            cancel,
+        } = job;
         let id = info.id;
-        tx.send(StatusUpdate::Done(
-            async {
-                let info = Arc::new(info);
-                let result = process_job(info.clone(), &tx).await;
+        let job_tracker = job_tracker.clone();
+        let job_store = job_store.clone();
+        let token = token.child_token();
 
-                let image_removed = tokio::fs::remove_file(&info.image_path).await.is_ok();
-                let audio_removed = tokio::fs::remove_file(&info.audio_path).await.is_ok();
-                let video_removed = tokio::fs::remove_file(&info.output_path).await.is_ok();
-                info!(%image_removed, %audio_removed, %video_removed, "cleanup");
+        if cancel.is_cancelled() {
+            // a restored job cancelled while queued, `cancel_job` already cleaned up
+            drop(worker);
+            debug!(%id, "skipping cancelled job");
+            tt.spawn(expire_job(id, JOB_EXPIRY, job_tracker, job_store, token));
+            continue;
+        }
 
-                result.map_err(Arc::new)
+        let slots = slots.clone();
+        tt.spawn(
+            async move {
+                // dropping `process_job` kills ffmpeg and aborts the upload
+                let result = select! {
+                    v = process_job(info.clone(), &status, &slots, config) => Some(v),
+                    _ = cancel.cancelled() => None
+                };
+
+                cleanup_job(&info).await;
+
+                let finished = match result {
+                    Some(result) => StatusUpdate::Done(result.map_err(Arc::new)),
+                    None => {
+                        info!("cancelled");
+                        StatusUpdate::Cancelled
+                    }
+                };
+                status.send(finished);
+                drop(status);
+                drop(worker);
+                debug!("job done");
+
+                expire_job(id, JOB_EXPIRY, job_tracker, job_store, token).await;
             }
-            .instrument(info_span!("processing", %id))
-            .await,
-        ))
-        .expect("a receiver is kept in the tracker at this point");
-        drop(tx);
-        debug!(%id, "job done");
-        let job_tracker = job_tracker.clone();
-        let token = token.child_token();
-        tt.spawn(async move {
-            select! {
-                _ = sleep(Duration::from_secs(15 * 60)) => {},
-                _ = token.cancelled() => {}
-            };
-            job_tracker
-                .remove_async(&id)
-                .await
-                .expect("entry got removed before job ended");
-            debug!(%id, "dropped job");
-        });
+            .instrument(info_span!("processing", %id)),
+        );
     }
 
+    job_queue.close();
     info!("finished dropping everything");
+    tt.close();
     tt.wait().await;
 }
 
-pub fn clamp_resize(
-    target_width: u32,
-    target_height: u32,
-    image_width: u32,
-    image_height: u32,
-) -> f64 {
-    let scale_ratio = target_width as f64 / image_width as f64;
-    let new_height = image_height as f64 * scale_ratio;
-    let height_ratio = target_height as f64 / new_height;
-    if height_ratio < 1.0 {
-        scale_ratio * height_ratio
-    } else {
-        scale_ratio
+#[cfg(test)]
+mod tests {
+    use std::future::IntoFuture;
+
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::HeaderMap;
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::{post, put};
+    use axum::{Json, Router};
+    use url::Url;
+
+    use super::*;
+
+    const SIZE: usize = 10_000;
+
+    /// what the mock does with the next chunk request
+    enum Mock {
+        Accept,
+        /// keeps only this many bytes of the chunk
+        Partial(usize),
+        /// keeps the chunk, then drops the connection without answering
+        Drop,
+        /// keeps the chunk, then never answers
+        Stall,
+        /// answers with this status and keeps nothing
+        Status(StatusCode),
+    }
+
+    #[derive(Default)]
+    struct MockUpload {
+        location: String,
+        /// answers 308 even once every byte is there
+        never_finish: bool,
+        session_errors: usize,
+        script: VecDeque<Mock>,
+        /// `Content-Range` of every request to the session
+        requests: Vec<String>,
+        received: Vec<u8>,
+    }
+
+    type Shared = Arc<Mutex<MockUpload>>;
+
+    async fn start_session(State(mock): State<Shared>) -> Response {
+        let mut mock = mock.lock().unwrap();
+        if mock.session_errors > 0 {
+            mock.session_errors -= 1;
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        (StatusCode::OK, [(header::LOCATION, mock.location.clone())]).into_response()
+    }
+
+    async fn put_chunk(State(mock): State<Shared>, headers: HeaderMap, body: Bytes) -> Response {
+        match chunk_response(&mock, &headers, &body) {
+            Some(response) => response,
+            None => {
+                sleep(Duration::from_secs(3600)).await;
+                unreachable!("the client gives up first");
+            }
+        }
+    }
+
+    /// `None` to never answer
+    fn chunk_response(mock: &Shared, headers: &HeaderMap, body: &Bytes) -> Option<Response> {
+        let mut guard = mock.lock().unwrap();
+        let range = headers[header::CONTENT_RANGE].to_str().unwrap().to_owned();
+        guard.requests.push(range.clone());
+
+        let start = range
+            .strip_prefix("bytes ")
+            .and_then(|range| range.split_once('-'))
+            .map(|(start, _)| start.parse::<usize>().unwrap());
+        let action = match start {
+            Some(_) => guard.script.pop_front().unwrap_or(Mock::Accept),
+            // status queries
+            None => match guard.script.front() {
+                Some(Mock::Status(_)) => guard.script.pop_front().unwrap(),
+                _ => Mock::Accept,
+            },
+        };
+        if let Mock::Status(status) = action {
+            return Some(status.into_response());
+        }
+        if start == Some(guard.received.len()) {
+            let keep = match action {
+                Mock::Partial(keep) => keep,
+                _ => body.len(),
+            };
+            guard.received.extend_from_slice(&body[..keep]);
+        }
+        if let Mock::Drop = action {
+            drop(guard);
+            // unwinds the connection task, the client sees the connection close
+            std::panic::resume_unwind(Box::new("dropping the connection"));
+        }
+        if let Mock::Stall = action {
+            return None;
+        }
+
+        Some(match guard.received.len() {
+            SIZE if !guard.never_finish => Json(json!({ "id": "video" })).into_response(),
+            0 => StatusCode::PERMANENT_REDIRECT.into_response(),
+            n => (
+                StatusCode::PERMANENT_REDIRECT,
+                [(header::RANGE, format!("bytes=0-{}", n - 1))],
+            )
+                .into_response(),
+        })
+    }
+
+    async fn mock_upload(
+        mock: MockUpload,
+        config: YoutubeConfig,
+    ) -> (Result<String, YTUploadError>, MockUpload) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}/", listener.local_addr().unwrap());
+        let mock = Arc::new(Mutex::new(MockUpload {
+            location: format!("{base}session"),
+            ..mock
+        }));
+        let router = Router::new()
+            .route("/upload/youtube/v3/videos", post(start_session))
+            .route("/session", put(put_chunk))
+            .with_state(mock.clone());
+        tokio::spawn(axum::serve(listener, router).into_future());
+
+        let data: Vec<u8> = (0..SIZE).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("upload_test_{}", Ulid::new()));
+        tokio::fs::write(&path, &data).await.unwrap();
+        let store_path = path.with_extension("store");
+        let store = JobStore::open(&store_path, &[0; 32]).unwrap();
+
+        let meta = Metadata {
+            filename: "video.mkv".into(),
+            title: Some("title".into()),
+            description: String::new(),
+            privacy: Privacy::Private,
+            tags: Vec::new(),
+            category: 10,
+            made_for_kids: false,
+            notify_subs: false,
+            publish_at: None,
+            default_language: None,
+            default_audio_language: None,
+            license: None,
+            embeddable: None,
+            public_stats_viewable: None,
+            recording_date: None,
+            localizations: BTreeMap::new(),
+        };
+        let auth = TokenClaim {
+            scope: Vec::new(),
+            access_token: "token".into(),
+            expires_at: OffsetDateTime::now_utc(),
+            refresh_token: String::new(),
+            user_id: String::new(),
+        };
+        let config = YoutubeConfig {
+            api_url: Url::parse(&base).unwrap(),
+            ..config
+        };
+        let result = upload(
+            File::open(&path).await.unwrap(),
+            SIZE as u64,
+            &meta,
+            &auth,
+            Container::Matroska,
+            &config,
+            &StatusHandle::new(Ulid::new(), store),
+        )
+        .await;
+
+        let _ = tokio::fs::remove_file(&path).await;
+        let _ = tokio::fs::remove_dir_all(&store_path).await;
+        let mock = std::mem::take(&mut *mock.lock().unwrap());
+        if result.is_ok() {
+            assert!(mock.received == data, "uploaded bytes differ");
+        }
+        (result, mock)
+    }
+
+    fn config() -> YoutubeConfig {
+        YoutubeConfig {
+            chunk_size: 4000,
+            retry_delay: 10,
+            retry_time: 60_000,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_from_acknowledged_offset() {
+        let mock = MockUpload {
+            script: [Mock::Partial(1000)].into(),
+            ..Default::default()
+        };
+        let (result, mock) = mock_upload(mock, config()).await;
+        assert_eq!(result.unwrap(), "video");
+        assert_eq!(
+            mock.requests,
+            [
+                "bytes 0-3999/10000",
+                "bytes 1000-4999/10000",
+                "bytes 5000-8999/10000",
+                "bytes 9000-9999/10000",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn queries_status_after_dropped_connection() {
+        let mock = MockUpload {
+            script: [Mock::Accept, Mock::Drop].into(),
+            ..Default::default()
+        };
+        let (result, mock) = mock_upload(mock, config()).await;
+        assert_eq!(result.unwrap(), "video");
+        assert_eq!(
+            mock.requests,
+            [
+                "bytes 0-3999/10000",
+                "bytes 4000-7999/10000",
+                "bytes */10000",
+                "bytes 8000-9999/10000",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn queries_status_after_stalled_request() {
+        let mock = MockUpload {
+            script: [Mock::Accept, Mock::Stall].into(),
+            ..Default::default()
+        };
+        let config = YoutubeConfig {
+            timeout: 200,
+            min_upload_rate: 1 << 30,
+            ..config()
+        };
+        let (result, mock) = mock_upload(mock, config).await;
+        assert_eq!(result.unwrap(), "video");
+        assert_eq!(
+            mock.requests,
+            [
+                "bytes 0-3999/10000",
+                "bytes 4000-7999/10000",
+                "bytes */10000",
+                "bytes 8000-9999/10000",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_with_backoff() {
+        let mock = MockUpload {
+            session_errors: 1,
+            script: [
+                Mock::Status(StatusCode::SERVICE_UNAVAILABLE),
+                Mock::Status(StatusCode::TOO_MANY_REQUESTS),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let (result, mock) = mock_upload(mock, config()).await;
+        assert_eq!(result.unwrap(), "video");
+        assert_eq!(
+            mock.requests,
+            [
+                "bytes 0-3999/10000",
+                "bytes */10000",
+                "bytes */10000",
+                "bytes 0-3999/10000",
+                "bytes 4000-7999/10000",
+                "bytes 8000-9999/10000",
+            ]
+        );
+        // 10ms for the session, then 10ms and 20ms for the chunk, jitter on top
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[tokio::test]
+    async fn backs_off_when_nothing_is_gained() {
+        let mock = MockUpload {
+            never_finish: true,
+            ..Default::default()
+        };
+        let config = YoutubeConfig {
+            retry_delay: 50,
+            retry_time: 1500,
+            ..config()
+        };
+        let (result, mock) = mock_upload(mock, config).await;
+        assert!(
+            matches!(result, Err(YTUploadError::RetriesExhausted(_))),
+            "{result:?}"
+        );
+        // three chunks, then status queries spaced out by the backoff
+        assert_eq!(mock.requests[3], "bytes */10000");
+        assert!(mock.requests.len() < 10, "{:?}", mock.requests);
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_the_budget_is_spent() {
+        let mock = MockUpload {
+            script: (0..100)
+                .map(|_| Mock::Status(StatusCode::SERVICE_UNAVAILABLE))
+                .collect(),
+            ..Default::default()
+        };
+        let config = YoutubeConfig {
+            retry_delay: 100,
+            retry_time: 300,
+            ..config()
+        };
+        let start = Instant::now();
+        let (result, mock) = mock_upload(mock, config).await;
+        assert!(
+            matches!(result, Err(YTUploadError::RetriesExhausted(_))),
+            "{result:?}"
+        );
+        assert!(mock.received.is_empty());
+        assert!(start.elapsed() < Duration::from_secs(5));
     }
 }
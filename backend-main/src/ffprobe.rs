@@ -1,7 +1,8 @@
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::process::Stdio;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::process::Command;
 use tracing::debug;
@@ -23,14 +24,23 @@ struct ProbeStream {
     codec_name: String,
     codec_type: String,
     start_time: Option<String>,
-    duration: String,
+    /// missing on cover art streams
+    duration: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    #[serde(default)]
+    disposition: HashMap<String, i64>,
 }
 
 #[derive(Deserialize, Debug)]
 #[allow(unused)]
 struct ProbeFormat {
+    /// comma separated when ffmpeg can't tell related formats apart
+    format_name: String,
     start_time: Option<String>,
     duration: String,
+    #[serde(default)]
+    tags: HashMap<String, String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -40,7 +50,107 @@ struct Probe {
     format: ProbeFormat,
 }
 
-pub async fn get_duration_ffprobe(path: impl AsRef<OsStr>) -> Result<f64, FfprobeError> {
+#[derive(Debug, Clone)]
+pub struct AudioProbe {
+    /// seconds, including the start time
+    pub duration: f64,
+    /// codec of the first audio stream, as named by ffmpeg
+    pub codec: String,
+    /// container as detected by ffprobe
+    pub format: String,
+    pub tags: AudioTags,
+    /// attached picture (APIC, PICTURE, covr)
+    pub cover: Option<CoverStream>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CoverStream {
+    pub index: i64,
+    pub codec: String,
+}
+
+/// the tags we care about, from the container or the audio stream
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AudioTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub date: Option<String>,
+    pub genre: Option<String>,
+    pub isrc: Option<String>,
+    pub comment: Option<String>,
+}
+
+impl AudioTags {
+    /// container tags win, ogg and opus keep theirs on the stream
+    fn collect(format: &HashMap<String, String>, stream: &HashMap<String, String>) -> Self {
+        let lookup = |keys: &[&str]| {
+            [format, stream].into_iter().find_map(|tags| {
+                keys.iter().find_map(|key| {
+                    tags.iter()
+                        .find(|(k, v)| k.eq_ignore_ascii_case(key) && !v.trim().is_empty())
+                        .map(|(_, v)| v.trim().to_owned())
+                })
+            })
+        };
+        Self {
+            title: lookup(&["title"]),
+            artist: lookup(&["artist", "album_artist"]),
+            album: lookup(&["album"]),
+            date: lookup(&["date", "year"]),
+            genre: lookup(&["genre"]),
+            isrc: lookup(&["isrc", "tsrc"]),
+            comment: lookup(&["comment", "description"]),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "title" => self.title.as_deref(),
+            "artist" => self.artist.as_deref(),
+            "album" => self.album.as_deref(),
+            "date" => self.date.as_deref(),
+            "genre" => self.genre.as_deref(),
+            "isrc" => self.isrc.as_deref(),
+            "comment" => self.comment.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// fills `{tag}` placeholders, lines with a missing tag are left out
+    pub fn render(&self, template: &str) -> Option<String> {
+        render_template(template, |key| self.get(key))
+    }
+}
+
+/// fills `{key}` placeholders from `lookup`, lines with a missing value are left out
+pub fn render_template<'a>(
+    template: &str,
+    lookup: impl Fn(&str) -> Option<&'a str>,
+) -> Option<String> {
+    let mut lines = Vec::new();
+    'lines: for line in template.lines() {
+        let mut out = String::new();
+        let mut rest = line;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            let Some(value) = lookup(&rest[start + 1..start + end]) else {
+                continue 'lines;
+            };
+            out.push_str(&rest[..start]);
+            out.push_str(value);
+            rest = &rest[start + end + 1..];
+        }
+        out.push_str(rest);
+        lines.push(out);
+    }
+    let rendered = lines.join("\n");
+    (!rendered.trim().is_empty()).then_some(rendered)
+}
+
+pub async fn probe_audio(path: impl AsRef<OsStr>) -> Result<AudioProbe, FfprobeError> {
     let mut cmd = Command::new("ffprobe");
     cmd.arg("-hide_banner")
         .arg("-show_format")
@@ -55,23 +165,48 @@ pub async fn get_duration_ffprobe(path: impl AsRef<OsStr>) -> Result<f64, Ffprob
     let out = child.wait_with_output().await?;
     let probe: Probe = serde_json::from_slice(&out.stdout)?;
 
-    let mut duration = None;
+    let mut audio = None;
+    let mut cover = None;
 
+    let format_tags = probe.format.tags;
     for stream in probe.streams.into_iter() {
-        if stream.codec_type == "audio" {
+        if stream.codec_type == "audio" && audio.is_none() {
             debug!("found audio stream");
-            let d: f64 = stream.duration.parse().expect("ffprobe output to be valid");
+            let d: f64 = stream
+                .duration
+                .as_ref()
+                .unwrap_or(&probe.format.duration)
+                .parse()
+                .expect("ffprobe output to be valid");
             let s: f64 = stream
                 .start_time
                 .map(|s| s.parse().expect("ffprobe output to be valid"))
                 .unwrap_or(0.0);
 
-            debug!("duration: {d}, start time: {s}");
+            debug!(
+                "duration: {d}, start time: {s}, codec: {}",
+                stream.codec_name
+            );
 
-            duration = Some(s + d);
-            break;
+            audio = Some((s + d, stream.codec_name, stream.tags));
+        } else if stream.codec_type == "video"
+            && stream.disposition.get("attached_pic") == Some(&1)
+            && cover.is_none()
+        {
+            debug!(codec = stream.codec_name, "found cover art");
+            cover = Some(CoverStream {
+                index: stream.index,
+                codec: stream.codec_name,
+            });
         }
     }
 
-    duration.ok_or(FfprobeError::NoStreams)
+    let audio = audio.map(|(duration, codec, tags)| AudioProbe {
+        duration,
+        codec,
+        format: probe.format.format_name,
+        tags: AudioTags::collect(&format_tags, &tags),
+        cover,
+    });
+    audio.ok_or(FfprobeError::NoStreams)
 }
@@ -0,0 +1,365 @@
+use std::fmt::{self, Display};
+
+/// a stream going in or out of a chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pad {
+    /// `[1]`, an input file by its position
+    Input(usize),
+    /// `[1:a]`, streams of one type from an input file
+    Stream(usize, &'static str),
+    /// `[name]`, connects chains
+    Label(String),
+}
+
+impl Pad {
+    /// labels are never escaped, so only identifiers are allowed
+    pub fn label(name: impl Into<String>) -> Self {
+        let name = name.into();
+        assert!(
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "invalid pad label {name:?}"
+        );
+        Self::Label(name)
+    }
+}
+
+impl Display for Pad {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Input(index) => write!(f, "[{index}]"),
+            Self::Stream(index, kind) => write!(f, "[{index}:{kind}]"),
+            Self::Label(name) => write!(f, "[{name}]"),
+        }
+    }
+}
+
+/// one filter with its options, values are escaped when written
+#[derive(Debug, Clone)]
+pub struct Filter {
+    name: &'static str,
+    options: Vec<(Option<&'static str>, String)>,
+}
+
+impl Filter {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            options: Vec::new(),
+        }
+    }
+
+    /// `key=value`
+    pub fn opt(mut self, key: &'static str, value: impl Display) -> Self {
+        self.options.push((Some(key), value.to_string()));
+        self
+    }
+
+    /// a value without a key, in the order the filter expects them
+    pub fn value(mut self, value: impl Display) -> Self {
+        self.options.push((None, value.to_string()));
+        self
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)?;
+        for (i, (key, value)) in self.options.iter().enumerate() {
+            f.write_str(if i == 0 { "=" } else { ":" })?;
+            if let Some(key) = key {
+                write!(f, "{key}=")?;
+            }
+            f.write_str(&escape(value))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Chain {
+    inputs: Vec<Pad>,
+    filters: Vec<Filter>,
+    outputs: Vec<Pad>,
+}
+
+impl Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for pad in &self.inputs {
+            write!(f, "{pad}")?;
+        }
+        for (i, filter) in self.filters.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{filter}")?;
+        }
+        for pad in &self.outputs {
+            write!(f, "{pad}")?;
+        }
+        Ok(())
+    }
+}
+
+/// the value of `-filter_complex`
+#[derive(Debug, Clone, Default)]
+pub struct Graph {
+    chains: Vec<Chain>,
+}
+
+impl Graph {
+    /// `inputs` through `filters` one after another into `outputs`
+    pub fn chain(
+        &mut self,
+        inputs: impl IntoIterator<Item = Pad>,
+        filters: impl IntoIterator<Item = Filter>,
+        outputs: impl IntoIterator<Item = Pad>,
+    ) {
+        let filters: Vec<Filter> = filters.into_iter().collect();
+        assert!(!filters.is_empty(), "filter chains can't be empty");
+        self.chains.push(Chain {
+            inputs: inputs.into_iter().collect(),
+            filters,
+            outputs: outputs.into_iter().collect(),
+        });
+    }
+}
+
+impl Display for Graph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, chain) in self.chains.iter().enumerate() {
+            if i > 0 {
+                f.write_str(";")?;
+            }
+            write!(f, "{chain}")?;
+        }
+        Ok(())
+    }
+}
+
+/// escapes a filter option value, then the filtergraph around it
+pub fn escape(value: &str) -> String {
+    let mut option = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '\'' | ':') {
+            option.push('\\');
+        }
+        option.push(c);
+    }
+    let mut graph = String::with_capacity(option.len());
+    for c in option.chars() {
+        if matches!(c, '\\' | '\'' | '[' | ']' | ',' | ';') {
+            graph.push('\\');
+        }
+        graph.push(c);
+    }
+    graph
+}
+
+/// width and height in pixels, written as `WxH`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl From<(u32, u32)> for Size {
+    fn from((width, height): (u32, u32)) -> Self {
+        Self { width, height }
+    }
+}
+
+impl Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+impl Size {
+    /// largest size with the same aspect ratio inside `bounds`, at least 1x1
+    pub fn fit_within(self, bounds: Size) -> Size {
+        let scale = f64::min(
+            bounds.width as f64 / self.width.max(1) as f64,
+            bounds.height as f64 / self.height.max(1) as f64,
+        );
+        Size {
+            width: ((self.width as f64 * scale) as u32).clamp(1, bounds.width.max(1)),
+            height: ((self.height as f64 * scale) as u32).clamp(1, bounds.height.max(1)),
+        }
+    }
+
+    /// top left corner that centers `self` on `outer`, negative where it sticks out
+    pub fn centered_on(self, outer: Size) -> (i64, i64) {
+        (
+            (outer.width as i64 - self.width as i64) / 2,
+            (outer.height as i64 - self.height as i64) / 2,
+        )
+    }
+
+    /// the size needed to place `self` at `(x, y)`
+    pub fn offset_by(self, x: u32, y: u32) -> Size {
+        Size {
+            width: self.width.saturating_add(x),
+            height: self.height.saturating_add(y),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Fit, FrameConfig};
+    use crate::ffmpeg::frame_segment;
+
+    #[test]
+    fn escapes_option_and_graph_level() {
+        assert_eq!(escape("plain-value_1.5"), "plain-value_1.5");
+        assert_eq!(escape(":"), r"\\:");
+        assert_eq!(escape(r"\"), r"\\\\");
+        assert_eq!(escape("'"), r"\\\'");
+        assert_eq!(escape("[]"), r"\[\]");
+        assert_eq!(escape(",;"), r"\,\;");
+        assert_eq!(escape(r"a:b\c'd[e]f,g;h"), r"a\\:b\\\\c\\\'d\[e\]f\,g\;h");
+    }
+
+    #[test]
+    fn writes_filters() {
+        assert_eq!(Filter::new("null").to_string(), "null");
+        let crop = Filter::new("crop")
+            .value(640)
+            .value(360)
+            .opt("x", "(iw-ow)/2")
+            .opt("exact", 1);
+        assert_eq!(crop.to_string(), "crop=640:360:x=(iw-ow)/2:exact=1");
+        let color = Filter::new("color").opt("color", "red:size=1x1[x];");
+        assert_eq!(color.to_string(), r"color=color=red\\:size=1x1\[x\]\;");
+    }
+
+    #[test]
+    fn writes_graphs() {
+        let mut graph = Graph::default();
+        graph.chain(
+            [Pad::Input(0)],
+            [Filter::new("scale")
+                .opt("size", Size::from((1280, 720)))
+                .opt("flags", "lanczos")],
+            [Pad::label("a")],
+        );
+        graph.chain(
+            [Pad::Stream(1, "a")],
+            [
+                Filter::new("showwaves").opt("mode", "cline"),
+                Filter::new("format").value("gray"),
+            ],
+            [Pad::label("w")],
+        );
+        graph.chain(
+            [Pad::label("a"), Pad::label("w")],
+            [Filter::new("overlay").value(0).value(0).opt("shortest", 1)],
+            [Pad::label("out")],
+        );
+        graph.chain([], [Filter::new("nullsrc")], []);
+        assert_eq!(
+            graph.to_string(),
+            "[0]scale=size=1280x720:flags=lanczos[a];[1:a]showwaves=mode=cline,format=gray[w];[a][w]overlay=0:0:shortest=1[out];nullsrc"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid pad label")]
+    fn rejects_unsafe_labels() {
+        Pad::label("a];[b");
+    }
+
+    #[test]
+    fn geometry() {
+        let bounds = Size::from((1280, 720));
+        assert_eq!(
+            Size::from((500, 500)).fit_within(bounds),
+            Size::from((720, 720))
+        );
+        assert_eq!(
+            Size::from((2000, 500)).fit_within(bounds),
+            Size::from((1280, 320))
+        );
+        assert_eq!(
+            Size::from((1, 1000)).fit_within(bounds),
+            Size::from((1, 720))
+        );
+
+        let frame = Size::from((1920, 1080));
+        assert_eq!(Size::from((720, 720)).centered_on(frame), (600, 180));
+        // bigger than the frame sticks out on every side instead of wrapping around
+        assert_eq!(Size::from((2000, 1200)).centered_on(frame), (-40, -60));
+
+        assert_eq!(frame.offset_by(10, 20), Size::from((1930, 1100)));
+        assert_eq!(
+            Size::from((u32::MAX - 5, 10)).offset_by(10, 5),
+            Size::from((u32::MAX, 15))
+        );
+        assert_eq!(frame.to_string(), "1920x1080");
+    }
+
+    fn frame_graph(frame: &FrameConfig, image_size: (u32, u32)) -> String {
+        let hold = vec![
+            Filter::new("loop").value(-1),
+            Filter::new("setpts").value("3/TB"),
+        ];
+        let mut graph = Graph::default();
+        frame_segment(&mut graph, frame, Pad::Input(0), 0, image_size.into(), hold);
+        graph.to_string()
+    }
+
+    #[test]
+    fn frame_resize() {
+        let frame = FrameConfig {
+            fit: Fit::Resize,
+            ..Default::default()
+        };
+        assert_eq!(
+            frame_graph(&frame, (500, 500)),
+            "color=color=black:size=1920x1080[bg0];color=color=black:size=1920x1080[frame_bg0];[0]scale=size=720x720:flags=lanczos[image0];[frame_bg0][image0]overlay=600:180[frame0];[bg0][frame0]overlay=0:0,loop=-1,setpts=3/TB[segment0]"
+        );
+    }
+
+    #[test]
+    fn frame_cover() {
+        let frame = FrameConfig {
+            fit: Fit::Cover,
+            focus: (0.5, 0.25),
+            ..Default::default()
+        };
+        assert_eq!(
+            frame_graph(&frame, (500, 500)),
+            "color=color=black:size=1920x1080[bg0];color=color=black:size=1920x1080[frame_bg0];[0]scale=size=1280x720:force_original_aspect_ratio=increase:flags=lanczos,crop=1280:720:(iw-ow)*0.5:(ih-oh)*0.25[image0];[frame_bg0][image0]overlay=320:180[frame0];[bg0][frame0]overlay=0:0,loop=-1,setpts=3/TB[segment0]"
+        );
+    }
+
+    #[test]
+    fn frame_blur_fill() {
+        let frame = FrameConfig {
+            fit: Fit::BlurFill,
+            ..Default::default()
+        };
+        assert_eq!(
+            frame_graph(&frame, (500, 500)),
+            "color=color=black:size=1920x1080[bg0];[0]split[source0][blur0];[blur0]scale=size=1920x1080:force_original_aspect_ratio=increase,crop=1920:1080,gblur=sigma=40,colorchannelmixer=rr=0.5:gg=0.5:bb=0.5,setsar=1[frame_bg0];[source0]scale=size=720x720:flags=lanczos[image0];[frame_bg0][image0]overlay=600:180[frame0];[bg0][frame0]overlay=0:0,loop=-1,setpts=3/TB[segment0]"
+        );
+    }
+
+    #[test]
+    fn frame_smaller_than_image() {
+        let frame = FrameConfig {
+            fit: Fit::Stretch,
+            x: 10,
+            y: 20,
+            frame_size: (1280, 720),
+            image_size: (1920, 1080),
+            frame_color: "white@0.5".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            frame_graph(&frame, (500, 500)),
+            "color=color=black:size=1290x740[bg0];color=color=white@0.5:size=1280x720[frame_bg0];[0]scale=size=1920x1080:flags=lanczos[image0];[frame_bg0][image0]overlay=-320:-180[frame0];[bg0][frame0]overlay=10:20,loop=-1,setpts=3/TB[segment0]"
+        );
+    }
+}
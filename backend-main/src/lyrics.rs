@@ -0,0 +1,388 @@
+use std::fmt::Write;
+
+use thiserror::Error;
+
+use crate::error::UploadError;
+
+#[derive(Error, Debug)]
+pub enum LyricsError {
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: &'static str },
+    #[error("no timed lines found")]
+    Empty,
+}
+
+#[derive(Debug, Clone)]
+pub struct LyricLine {
+    /// seconds
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+/// `[hh:]mm:ss[.,]fff` as used by srt and webvtt
+fn cue_timestamp(value: &str) -> Option<f64> {
+    let (clock, fraction) = value.split_once([',', '.'])?;
+    let mut seconds = 0.0;
+    let parts: Vec<&str> = clock.split(':').collect();
+    if !(2..=3).contains(&parts.len()) {
+        return None;
+    }
+    for part in parts {
+        seconds = seconds * 60.0 + part.parse::<u32>().ok()? as f64;
+    }
+    Some(seconds + format!("0.{fraction}").parse::<f64>().ok()?)
+}
+
+/// `mm:ss[.xx]` as used by lrc
+fn lrc_timestamp(value: &str) -> Option<f64> {
+    let (minutes, seconds) = value.split_once(':')?;
+    let seconds: f64 = seconds.parse().ok()?;
+    (seconds.is_finite() && seconds >= 0.0)
+        .then_some(minutes.parse::<u32>().ok()? as f64 * 60.0 + seconds)
+}
+
+/// srt and webvtt, cues separated by blank lines
+fn parse_cues(text: &str) -> Result<Vec<LyricLine>, LyricsError> {
+    let mut lines = Vec::new();
+    let mut current: Option<LyricLine> = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            lines.extend(current.take());
+            continue;
+        }
+        if let Some((start, rest)) = line.split_once("-->") {
+            let syntax = |message| LyricsError::Syntax {
+                line: i + 1,
+                message,
+            };
+            let start = cue_timestamp(start.trim()).ok_or(syntax("invalid start time"))?;
+            // webvtt cue settings follow the end time
+            let end = rest
+                .split_whitespace()
+                .next()
+                .and_then(cue_timestamp)
+                .ok_or(syntax("invalid end time"))?;
+            if end <= start {
+                return Err(syntax("cue ends before it starts"));
+            }
+            lines.extend(current.take());
+            current = Some(LyricLine {
+                start,
+                end,
+                text: String::new(),
+            });
+        } else if let Some(cue) = current.as_mut() {
+            if !cue.text.is_empty() {
+                cue.text.push('\n');
+            }
+            cue.text.push_str(line);
+        }
+        // cue numbers, the WEBVTT header, NOTE and STYLE blocks
+    }
+    lines.extend(current);
+    lines.retain(|line| !line.text.is_empty());
+    Ok(lines)
+}
+
+/// each line lasts until the next one, the last one until the end of the audio.
+/// `[offset:ms]` moves every line earlier, or later when negative
+fn parse_lrc(text: &str) -> Result<Vec<LyricLine>, LyricsError> {
+    let mut stamps = Vec::new();
+    let mut offset = 0.0;
+    for (i, line) in text.lines().enumerate() {
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+        while let Some((tag, after)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+            match lrc_timestamp(tag) {
+                Some(time) => times.push(time),
+                None if times.is_empty() && tag.starts_with("offset:") => {
+                    let ms: i64 =
+                        tag["offset:".len()..]
+                            .trim()
+                            .parse()
+                            .map_err(|_| LyricsError::Syntax {
+                                line: i + 1,
+                                message: "invalid offset",
+                            })?;
+                    offset = ms as f64 / 1000.0;
+                    break;
+                }
+                // [ar:...], [ti:...] and friends
+                None if tag.contains(':') && times.is_empty() => break,
+                None => {
+                    return Err(LyricsError::Syntax {
+                        line: i + 1,
+                        message: "invalid timestamp",
+                    })
+                }
+            }
+            rest = after;
+        }
+        for time in times {
+            stamps.push((time, rest.trim().to_owned()));
+        }
+    }
+    for (time, _) in &mut stamps {
+        *time = (*time - offset).max(0.0);
+    }
+    stamps.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut lines = Vec::with_capacity(stamps.len());
+    for (i, (start, text)) in stamps.iter().enumerate() {
+        // empty lines only mark where the previous one ends
+        if text.is_empty() {
+            continue;
+        }
+        lines.push(LyricLine {
+            start: *start,
+            end: stamps.get(i + 1).map_or(f64::INFINITY, |next| next.0),
+            text: text.clone(),
+        });
+    }
+    Ok(lines)
+}
+
+pub fn parse_lyrics(text: &str) -> Result<Vec<LyricLine>, LyricsError> {
+    let text = text.trim_start_matches('\u{feff}');
+    let lines = if text.trim_start().starts_with("WEBVTT") || text.contains("-->") {
+        parse_cues(text)?
+    } else {
+        parse_lrc(text)?
+    };
+    if lines.is_empty() {
+        return Err(LyricsError::Empty);
+    }
+    Ok(lines)
+}
+
+/// rejects lines out of order or starting after the audio ends and cuts the rest to fit
+pub fn fit_lyrics(lines: &mut [LyricLine], duration: f64) -> Result<(), UploadError> {
+    let mut previous = 0.0;
+    for (i, line) in lines.iter_mut().enumerate() {
+        let reason = if line.start < previous {
+            "before the line above it"
+        } else if line.start >= duration {
+            "after the audio ends"
+        } else {
+            previous = line.start;
+            line.end = line.end.min(duration);
+            continue;
+        };
+        return Err(UploadError::LyricsTiming {
+            line: i + 1,
+            value: line.start,
+            duration,
+            reason,
+        });
+    }
+    Ok(())
+}
+
+pub fn to_srt(lines: &[LyricLine]) -> String {
+    let timestamp = |t: f64| {
+        let ms = (t * 1000.0).round() as u64;
+        format!(
+            "{:02}:{:02}:{:02},{:03}",
+            ms / 3_600_000,
+            ms / 60_000 % 60,
+            ms / 1000 % 60,
+            ms % 1000
+        )
+    };
+
+    let mut out = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        let _ = write!(
+            out,
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            timestamp(line.start),
+            timestamp(line.end),
+            line.text
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn times(lines: &[LyricLine]) -> Vec<(f64, f64, &str)> {
+        lines
+            .iter()
+            .map(|line| (line.start, line.end, line.text.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_lrc() {
+        let lines =
+            parse_lyrics("[ti:Song]\n[ar:Artist]\n[00:01.00]First\n[00:03.50] Second \n[00:05.00]")
+                .unwrap();
+        assert_eq!(times(&lines), [(1.0, 3.5, "First"), (3.5, 5.0, "Second")]);
+    }
+
+    #[test]
+    fn parses_multi_stamp_lrc() {
+        let lines = parse_lyrics("[00:10.00][00:01.00]Chorus\n[00:05.00]Verse").unwrap();
+        assert_eq!(
+            times(&lines),
+            [
+                (1.0, 5.0, "Chorus"),
+                (5.0, 10.0, "Verse"),
+                (10.0, f64::INFINITY, "Chorus")
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_lrc_offset() {
+        let lines = parse_lyrics("[offset:+500]\n[00:00.25]A\n[00:02.00]B").unwrap();
+        assert_eq!(times(&lines), [(0.0, 1.5, "A"), (1.5, f64::INFINITY, "B")]);
+        let lines = parse_lyrics("[offset:-250]\n[00:01.00]A").unwrap();
+        assert_eq!(lines[0].start, 1.25);
+        let err = parse_lyrics("[offset:soon]\n[00:01.00]A").unwrap_err();
+        assert!(matches!(
+            err,
+            LyricsError::Syntax {
+                line: 1,
+                message: "invalid offset"
+            }
+        ));
+    }
+
+    #[test]
+    fn parses_srt() {
+        let lines = parse_lyrics(
+            "\u{feff}1
+00:00:01,000 --> 00:00:02,500
+Hello
+world
+
+2
+00:00:03,000 --> 00:00:04,000
+Again
+",
+        )
+        .unwrap();
+        assert_eq!(
+            times(&lines),
+            [(1.0, 2.5, "Hello\nworld"), (3.0, 4.0, "Again")]
+        );
+    }
+
+    #[test]
+    fn parses_webvtt() {
+        let lines = parse_lyrics(
+            "WEBVTT
+
+NOTE a comment
+
+00:01.000 --> 00:02.000 align:start
+One
+
+01:00:00.500 --> 01:00:01.000
+Two
+",
+        )
+        .unwrap();
+        assert_eq!(times(&lines), [(1.0, 2.0, "One"), (3600.5, 3601.0, "Two")]);
+    }
+
+    #[test]
+    fn rejects_malformed_timestamps() {
+        let cases = [
+            ("[00:01.00]ok\n[00:02.00][1:xx]bad", 2, "invalid timestamp"),
+            ("[00:01.00]ok\n[late]bad", 2, "invalid timestamp"),
+            ("00:00:01 --> 00:00:02,000\nx", 1, "invalid start time"),
+            ("00:00:01,000 --> 00:00:0x,000\nx", 1, "invalid end time"),
+            (
+                "00:00:02,000 --> 00:00:01,000\nx",
+                1,
+                "cue ends before it starts",
+            ),
+        ];
+        for (text, line, message) in cases {
+            let err = parse_lyrics(text).unwrap_err();
+            assert!(
+                matches!(err, LyricsError::Syntax { line: l, message: m } if l == line && m == message),
+                "{text:?}: {err}"
+            );
+        }
+        assert!(matches!(
+            parse_lyrics("[ti:Nothing]\n"),
+            Err(LyricsError::Empty)
+        ));
+    }
+
+    #[test]
+    fn fits_lyrics_to_the_audio() {
+        let mut lines = parse_lyrics("[00:01.00]A\n[00:03.00]B").unwrap();
+        fit_lyrics(&mut lines, 10.0).unwrap();
+        assert_eq!(times(&lines), [(1.0, 3.0, "A"), (3.0, 10.0, "B")]);
+
+        let text = "00:00:01,000 --> 00:00:02,000\nA\n\n00:00:03,000 --> 00:00:04,000\nB";
+        let mut lines = parse_lyrics(text).unwrap();
+        fit_lyrics(&mut lines, 3.5).unwrap();
+        assert_eq!(lines[1].end, 3.5);
+
+        let mut lines = parse_lyrics(text).unwrap();
+        let err = fit_lyrics(&mut lines, 3.0).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                UploadError::LyricsTiming {
+                    line: 2,
+                    reason: "after the audio ends",
+                    ..
+                }
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_order_lines() {
+        let mut lines =
+            parse_lyrics("00:00:05,000 --> 00:00:06,000\nA\n\n00:00:03,000 --> 00:00:04,000\nB")
+                .unwrap();
+        let err = fit_lyrics(&mut lines, 10.0).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                UploadError::LyricsTiming {
+                    line: 2,
+                    reason: "before the line above it",
+                    ..
+                }
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn writes_srt() {
+        let lines = [
+            LyricLine {
+                start: 1.0,
+                end: 2.5,
+                text: "Hello\nworld".into(),
+            },
+            LyricLine {
+                start: 3661.5,
+                end: 3662.0,
+                text: "Later".into(),
+            },
+        ];
+        let srt = to_srt(&lines);
+        assert_eq!(
+            srt,
+            "1\n00:00:01,000 --> 00:00:02,500\nHello\nworld\n\n2\n01:01:01,500 --> 01:01:02,000\nLater\n\n"
+        );
+        assert_eq!(times(&parse_lyrics(&srt).unwrap()), times(&lines));
+    }
+}
@@ -1,11 +1,15 @@
-#![feature(generic_arg_infer, duration_constructors, duration_millis_float)]
+#![feature(duration_constructors, duration_millis_float)]
 
 mod app;
 mod auth;
 mod config;
+mod cue;
 mod error;
 mod ffmpeg;
 mod ffprobe;
+mod filtergraph;
+mod lyrics;
+mod store;
 mod util;
 
 use std::net::SocketAddr;
@@ -14,19 +18,20 @@ use std::sync::Arc;
 use app::MAX_DESC;
 use color_eyre::eyre::{self, bail, Context};
 use ffmpeg::JobTracker;
-use image::image_dimensions;
+use image::{image_dimensions, ImageFormat};
 use jwt_simple::algorithms::{Ed25519KeyPair, EdDSAKeyPairLike};
 use tokio::net::TcpListener;
-use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
 use tracing::level_filters::LevelFilter;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{util::SubscriberInitExt, EnvFilter};
 
 use crate::app::AppState;
-use crate::config::Config;
-use crate::ffmpeg::ffmpeg_task;
+use crate::config::{Config, YOUTUBE_CHUNK_ALIGN};
+use crate::ffmpeg::{ffmpeg_task, restore_jobs, JobQueue};
+use crate::store::JobStore;
+use crate::util::{parse_hex_color, valid_ass_color, valid_color};
 
 #[allow(unused)]
 fn to_secret(v: &Ed25519KeyPair) -> [u8; 32] {
@@ -78,15 +83,132 @@ async fn main() -> eyre::Result<()> {
         image_dimensions(path)
             .context("coulnd't load watermark image! make sure it's a valid image file.")?;
     }
+    let watermark = &config.frame.watermark_style;
+    if !(0.0..=1.0).contains(&watermark.opacity)
+        || watermark
+            .scale
+            .is_some_and(|scale| !(scale > 0.0 && scale <= 1.0))
+    {
+        bail!("frame.watermark_style is out of range (opacity: 0..1, scale: 0..1)");
+    }
+
+    let visualizer = &config.frame.visualizer;
+    if !(0.0..=1.0).contains(&visualizer.opacity)
+        || !(1..=60).contains(&visualizer.fps)
+        || visualizer.size.0 == 0
+        || visualizer.size.1 == 0
+    {
+        bail!("frame.visualizer is out of range (opacity: 0..1, fps: 1..60, size: nonzero)");
+    }
+
+    let frame = &config.frame;
+    if !(0.0..=1.0).contains(&frame.focus.0)
+        || !(0.0..=1.0).contains(&frame.focus.1)
+        || !frame.blur.sigma.is_normal()
+        || frame.blur.sigma < 0.0
+        || !(0.0..=1.0).contains(&frame.blur.brightness)
+    {
+        bail!("frame is out of range (focus: 0..1, blur.sigma: positive, blur.brightness: 0..1)");
+    }
+    if !valid_color(&frame.frame_color) || !valid_color(&frame.void_color) {
+        bail!("frame.frame_color and frame.void_color have to be ffmpeg colours");
+    }
+    for layer in &frame.text {
+        if let Some(path) = layer.font.as_ref() {
+            std::fs::File::open(path)
+                .with_context(|| format!("couldn't open font file {}", path.display()))?;
+        }
+        if layer.size == 0
+            || ![&layer.color, &layer.outline_color, &layer.shadow_color]
+                .into_iter()
+                .all(|color| valid_color(color))
+        {
+            bail!("frame.text layers need a nonzero size and ffmpeg colours");
+        }
+    }
+    if !frame.enable && frame.text.iter().any(|layer| !layer.without_frame) {
+        warn!("frame is disabled, frame.text layers without without_frame won't be drawn");
+    }
+
+    let lyrics = &config.lyrics;
+    if !(1..=9).contains(&lyrics.alignment) || !(1..=60).contains(&lyrics.fps) {
+        bail!("lyrics is out of range (alignment: 1..9, fps: 1..60)");
+    }
+    // these end up in `force_style`, where commas separate the fields
+    if lyrics.font.is_empty()
+        || lyrics.font.contains([',', '='])
+        || lyrics.font.contains(char::is_control)
+    {
+        bail!("lyrics.font has to be a font name without commas or equals signs");
+    }
+    if !valid_ass_color(&lyrics.primary_color) || !valid_ass_color(&lyrics.outline_color) {
+        bail!("lyrics.primary_color and lyrics.outline_color have to be ASS colours (&HAABBGGRR)");
+    }
+    if let Some(dir) = lyrics.fonts_dir.as_ref() {
+        if !dir.is_dir() {
+            bail!("lyrics.fonts_dir {} is not a directory", dir.display());
+        }
+    }
+
+    if parse_hex_color(&config.default_cover.color).is_none() {
+        bail!("default_cover.color has to be #rrggbb");
+    }
+    if let Some(path) = config.default_cover.image.as_ref() {
+        image_dimensions(path).context("couldn't load default cover image")?;
+    }
+    // decoded formats are compared by their first extension, `jpg` rather than `jpeg`
+    if let Some(name) = config.formats.image.iter().find(|name| {
+        ImageFormat::from_extension(name).is_none_or(|format| format.extensions_str()[0] != *name)
+    }) {
+        bail!("formats.image contains {name:?}, which is not a known image extension");
+    }
+
+    let encoding = &config.encoding;
+    if let Some(name) = std::iter::once(&encoding.default)
+        .chain(&encoding.allowed)
+        .find(|name| !encoding.presets.contains_key(*name))
+    {
+        bail!("encoding preset {name:?} is used but not defined in encoding.presets");
+    }
+
+    let loudness = encoding.loudness;
+    if !(-70.0..=-5.0).contains(&loudness.integrated)
+        || !(-9.0..=0.0).contains(&loudness.true_peak)
+        || !(1.0..=50.0).contains(&loudness.lra)
+    {
+        bail!(
+            "encoding.loudness is out of range (integrated: -70..-5, true_peak: -9..0, lra: 1..50)"
+        );
+    }
+
+    let processing = config.limits.processing;
+    if processing.workers == 0 || processing.render == 0 || processing.upload == 0 {
+        bail!("limits.processing.workers, render and upload must all be at least 1");
+    }
+
+    if config.youtube.chunk_size == 0
+        || !config
+            .youtube
+            .chunk_size
+            .is_multiple_of(YOUTUBE_CHUNK_ALIGN)
+    {
+        bail!(
+            "youtube.chunk_size must be a multiple of {} bytes",
+            YOUTUBE_CHUNK_ALIGN
+        );
+    }
+    if config.youtube.timeout == 0 || config.youtube.min_upload_rate == 0 {
+        bail!("youtube.timeout and youtube.min_upload_rate must be nonzero");
+    }
 
     if !tokio::fs::try_exists(&config.temp_dir)
         .await
-        .with_context(|| format!("couldn't check if temp_dir {:?} exists", &config.temp_dir))?
+        .with_context(|| format!("couldn't check if temp_dir {:?} exists", config.temp_dir))?
     {
         debug!("creating temporary directory");
         tokio::fs::create_dir_all(&config.temp_dir)
             .await
-            .with_context(|| format!("couldn't create temp_dir {:?}", &config.temp_dir))?;
+            .with_context(|| format!("couldn't create temp_dir {:?}", config.temp_dir))?;
     }
 
     let socket_addr = SocketAddr::new(config.http.host, config.http.port);
@@ -95,14 +217,29 @@ async fn main() -> eyre::Result<()> {
     let axum_token = cancellation_token.child_token();
     let ffmpeg_token = cancellation_token.child_token();
 
-    let (job_sender, rx) = mpsc::channel(config.limits.processing.queue_size);
+    debug!("opening job store");
+    let job_store = JobStore::open(&config.job_store, &config.jwt_key.0)
+        .with_context(|| format!("couldn't open job store {:?}", config.job_store))?;
+
+    let job_queue = Arc::new(JobQueue::new(config.limits.processing.queue_size));
     let job_tracker = Arc::<JobTracker>::default();
-    let ffmpeg_task = tokio::spawn(ffmpeg_task(rx, job_tracker.clone(), ffmpeg_token));
+    let restored = restore_jobs(&job_store, &job_tracker, &config.temp_dir)
+        .await
+        .context("couldn't restore jobs")?;
+    let ffmpeg_task = tokio::spawn(ffmpeg_task(
+        job_queue.clone(),
+        job_tracker.clone(),
+        job_store.clone(),
+        restored,
+        config,
+        ffmpeg_token,
+    ));
 
     let app = app::new(AppState {
         config,
-        job_sender,
+        job_queue,
         job_tracker,
+        job_store,
         cancellation_token: cancellation_token.clone(),
         keypair: from_secret(config.jwt_key.0),
         reqwest: Default::default(),
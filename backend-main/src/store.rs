@@ -0,0 +1,164 @@
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use time::OffsetDateTime;
+use tracing::debug;
+use ulid::Ulid;
+
+use crate::ffmpeg::{JobInfo, StatusUpdate};
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("job store error: {0}")]
+    SledError(#[from] sled::Error),
+    #[error("failed to (de)serialize stored job: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("couldn't decrypt stored job, jwt_key might have changed")]
+    Decrypt,
+}
+
+/// last known status of a job, as sent over the websocket
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredStatus {
+    pub status: serde_json::Value,
+    /// set once the job is done, used to expire the entry
+    pub finished_at: Option<OffsetDateTime>,
+}
+
+/// a job that was queued or running when the application stopped
+pub struct UnfinishedJob {
+    pub id: Ulid,
+    /// fails when the stored copy can't be read anymore
+    pub info: Result<JobInfo, StoreError>,
+    pub status: Option<StoredStatus>,
+}
+
+/// on-disk copy of the job queue.
+///
+/// `jobs` holds everything needed to run a job again and is only kept until
+/// the job is done, `status` outlives it until the job expires.
+/// jobs carry the user's oauth tokens, so they're encrypted with a key derived from `key`.
+#[derive(Clone)]
+pub struct JobStore {
+    jobs: sled::Tree,
+    status: sled::Tree,
+    cipher: XChaCha20Poly1305,
+}
+
+impl JobStore {
+    pub fn open(path: impl AsRef<Path>, key: &[u8; 32]) -> Result<Self, StoreError> {
+        let mut job_key = [0; 32];
+        Hkdf::<Sha256>::new(None, key)
+            .expand(b"musngr job store", &mut job_key)
+            .expect("32 bytes is a valid length");
+        let db = sled::open(path)?;
+        Ok(Self {
+            jobs: db.open_tree("jobs")?,
+            status: db.open_tree("status")?,
+            cipher: XChaCha20Poly1305::new(&job_key.into()),
+        })
+    }
+
+    pub fn insert(&self, info: &JobInfo) -> Result<(), StoreError> {
+        self.jobs.insert(info.id.to_bytes(), self.seal(info)?)?;
+        self.set_status(info.id, &StatusUpdate::Queued)?;
+        Ok(())
+    }
+
+    /// the nonce followed by the encrypted json
+    fn seal(&self, info: &JobInfo) -> Result<Vec<u8>, StoreError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, serde_json::to_vec(info)?.as_slice())
+            .expect("jobs are far below the message size limit");
+        Ok([nonce.as_slice(), &sealed].concat())
+    }
+
+    fn open_sealed(&self, bytes: &[u8]) -> Result<JobInfo, StoreError> {
+        if bytes.len() < 24 {
+            return Err(StoreError::Decrypt);
+        }
+        let (nonce, sealed) = bytes.split_at(24);
+        let json = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), sealed)
+            .map_err(|_| StoreError::Decrypt)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    pub fn set_status(&self, id: Ulid, status: &StatusUpdate) -> Result<(), StoreError> {
+        let finished = status.is_finished();
+        let stored = StoredStatus {
+            status: serde_json::to_value(status)?,
+            finished_at: finished.then(OffsetDateTime::now_utc),
+        };
+        self.status
+            .insert(id.to_bytes(), serde_json::to_vec(&stored)?)?;
+        if finished {
+            // the job can't be resumed anymore, so drop the auth token and paths
+            self.jobs.remove(id.to_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn status(&self, id: Ulid) -> Result<Option<StoredStatus>, StoreError> {
+        match self.status.get(id.to_bytes())? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn remove(&self, id: Ulid) -> Result<(), StoreError> {
+        self.jobs.remove(id.to_bytes())?;
+        self.status.remove(id.to_bytes())?;
+        Ok(())
+    }
+
+    /// jobs that were queued or running when the application stopped
+    pub fn unfinished(&self) -> Result<Vec<UnfinishedJob>, StoreError> {
+        let mut unfinished = Vec::new();
+        for entry in self.jobs.iter() {
+            let (key, value) = entry?;
+            let id = Ulid::from_bytes(
+                key.as_ref()
+                    .try_into()
+                    .expect("keys are always written from a ulid"),
+            );
+            unfinished.push(UnfinishedJob {
+                id,
+                info: self.open_sealed(&value),
+                status: self.status(id)?,
+            });
+        }
+        Ok(unfinished)
+    }
+
+    /// jobs that are done but haven't expired yet, with the time they finished
+    pub fn finished(&self) -> Result<Vec<(Ulid, OffsetDateTime)>, StoreError> {
+        let mut finished = Vec::new();
+        for entry in self.status.iter() {
+            let (key, value) = entry?;
+            let id = Ulid::from_bytes(
+                key.as_ref()
+                    .try_into()
+                    .expect("keys are always written from a ulid"),
+            );
+            let stored: StoredStatus = serde_json::from_slice(&value)?;
+            match stored.finished_at {
+                Some(at) => finished.push((id, at)),
+                None if !self.jobs.contains_key(key)? => {
+                    debug!(%id, "removing status of a job that no longer exists");
+                    self.status.remove(id.to_bytes())?;
+                }
+                None => {}
+            }
+        }
+        Ok(finished)
+    }
+}
@@ -4,15 +4,17 @@ use std::sync::Arc;
 
 use axum::extract::multipart::Field;
 use futures_util::TryStreamExt;
-use image::{ImageDecoder, ImageFormat, ImageReader};
+use image::{ImageDecoder, ImageFormat, ImageReader, Rgb, RgbImage};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::process::Command;
 use tokio_util::io::StreamReader;
 use tracing::{error, warn};
 use ulid::Ulid;
 
 use crate::config::Config;
 use crate::error::UploadError;
+use crate::ffprobe::CoverStream;
 
 pub async fn take_upload<'a>(
     field: &mut Field<'a>,
@@ -65,31 +67,133 @@ pub fn get_file_info(
         .file_name()
         .ok_or(UploadError::InvalidFileName(String::new()))?
         .to_owned();
-    let extension = std::path::Path::new(&file_name)
-        .extension()
-        .ok_or_else(|| UploadError::InvalidFileName(file_name.to_string()))?;
-    let output_path = config
-        .temp_dir
-        .join(format!("{kind}_{id}.{}", extension.to_string_lossy()))
-        .into();
+    // the extension is added once the contents are known
+    let output_path = config.temp_dir.join(format!("{kind}_{id}")).into();
 
     Ok((file_name, output_path))
 }
 
-pub fn decode_image(
-    image_name: impl AsRef<Path>,
-    file: std::fs::File,
-) -> Result<((u32, u32), u64), UploadError> {
-    let image_format = mime_guess::from_path(image_name)
-        .first()
-        .ok_or(UploadError::BadRequest("unknown image extension"))?;
-    let mut image_reader = ImageReader::new(BufReader::new(file));
-    image_reader.set_format(
-        ImageFormat::from_mime_type(image_format)
-            .ok_or(UploadError::BadRequest("unsupported image mime type"))?,
-    );
+/// moves the file to `path` with the extension of its detected format
+pub async fn set_extension(
+    path: &Path,
+    extension: &str,
+) -> Result<Arc<std::path::Path>, UploadError> {
+    let renamed: Arc<Path> = path.with_extension(extension).into();
+    if *renamed != *path {
+        tokio::fs::rename(path, &renamed).await?;
+    }
+    Ok(renamed)
+}
+
+pub fn decode_image(file: std::fs::File) -> Result<(ImageFormat, (u32, u32), u64), UploadError> {
+    let image_reader = ImageReader::new(BufReader::new(file)).with_guessed_format()?;
+    let format = image_reader
+        .format()
+        .ok_or_else(|| UploadError::UnsupportedFormat {
+            subject: "image",
+            format: "unknown".into(),
+        })?;
 
     let decoder = image_reader.into_decoder()?;
 
-    Ok((decoder.dimensions(), decoder.total_bytes()))
+    Ok((format, decoder.dimensions(), decoder.total_bytes()))
+}
+
+/// ffprobe names families of formats together, `mov,mp4,m4a,3gp,3g2,mj2`
+pub fn audio_extension(config: &Config, format: &str) -> Option<&'static str> {
+    let name = format
+        .split(',')
+        .find(|name| config.formats.audio.iter().any(|allowed| allowed == name))?;
+    Some(match name {
+        "matroska" => "mka",
+        "mov" => "m4a",
+        "flac" => "flac",
+        "mp3" => "mp3",
+        "wav" => "wav",
+        "ogg" => "ogg",
+        "aiff" => "aiff",
+        "wv" => "wv",
+        // the path only has to be recognizable, ffmpeg probes the contents anyway
+        _ => "audio",
+    })
+}
+
+/// copies the attached picture out of an audio file, returns the written path
+pub async fn extract_cover(
+    audio: &Path,
+    cover: &CoverStream,
+    config: &Config,
+    id: Ulid,
+) -> Result<Arc<Path>, UploadError> {
+    let extension = match cover.codec.as_str() {
+        "mjpeg" => "jpg",
+        "png" => "png",
+        "bmp" => "bmp",
+        "webp" => "webp",
+        "gif" => "gif",
+        "tiff" => "tiff",
+        _ => return Err(UploadError::BadRequest("unsupported embedded cover format")),
+    };
+    let output_path: Arc<Path> = config
+        .temp_dir
+        .join(format!("image_{id}.{extension}"))
+        .into();
+    let status = Command::new("ffmpeg")
+        .arg("-hide_banner")
+        .arg("-nostats")
+        .arg("-i")
+        .arg(audio)
+        .arg("-map")
+        .arg(format!("0:{}", cover.index))
+        .arg("-c")
+        .arg("copy")
+        .arg("-frames:v")
+        .arg("1")
+        .arg("-f")
+        .arg("image2")
+        .arg(&*output_path)
+        .kill_on_drop(true)
+        .status()
+        .await?;
+    if status.success() {
+        Ok(output_path)
+    } else {
+        Err(UploadError::Other(
+            format!("failed to extract cover: {status}").into(),
+        ))
+    }
+}
+
+/// ffmpeg colour names and hex values with an optional `@alpha`,
+/// nothing that could end a filter argument
+pub fn valid_color(color: &str) -> bool {
+    !color.is_empty()
+        && color.len() <= 32
+        && color
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '#' | '@' | '.'))
+}
+
+/// `&HBBGGRR` or `&HAABBGGRR` as ass styles take them, optionally closed by `&`
+pub fn valid_ass_color(color: &str) -> bool {
+    color
+        .strip_prefix("&H")
+        .map(|hex| hex.strip_suffix('&').unwrap_or(hex))
+        .is_some_and(|hex| matches!(hex.len(), 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// `#rrggbb`
+pub fn parse_hex_color(color: &str) -> Option<[u8; 3]> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+/// a png of a single colour
+pub fn solid_image(path: &Path, size: (u32, u32), color: [u8; 3]) -> Result<(), UploadError> {
+    RgbImage::from_pixel(size.0, size.1, Rgb(color)).save(path)?;
+    Ok(())
 }